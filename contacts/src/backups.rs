@@ -0,0 +1,100 @@
+//! Archives of all our books, invites and local metadata, saved to the
+//! package VFS drive once a day and whenever the user asks. Only the newest
+//! `MAX_BACKUPS` are kept. See `State::archive` and `State::restore`.
+use crate::response::UpdateError;
+use kinode_process_lib::{vfs, Address};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+const DRIVE: &str = "backups";
+
+/// Seconds between scheduled backups.
+pub const INTERVAL: u64 = 24 * 60 * 60;
+
+/// How many backups to keep before deleting the oldest.
+const MAX_BACKUPS: usize = 7;
+
+/// Context of the timer for scheduled backups, to tell it apart from reminders.
+pub const TIMER_CONTEXT: &[u8] = b"backup";
+
+#[derive(Serialize, Deserialize)]
+pub struct Archive {
+    /// Seconds since the unix epoch.
+    pub created: u64,
+    /// Everything but the books, as we save it with `set_state`.
+    pub state: Vec<u8>,
+    /// Each book as a full automerge save.
+    pub books: HashMap<Uuid, Vec<u8>>,
+}
+
+/// A backup, as listed at `/backups`.
+#[derive(Debug, Serialize)]
+pub struct Backup {
+    pub name: String,
+    pub created: u64,
+}
+
+pub fn create_drive(our: &Address) -> anyhow::Result<()> {
+    vfs::create_drive(our.package_id(), DRIVE, None)?;
+    Ok(())
+}
+
+fn path(our: &Address, name: &str) -> String {
+    format!("/{}/{}/{}", our.package_id(), DRIVE, name)
+}
+
+/// Our backups, oldest first.
+pub fn list(our: &Address) -> anyhow::Result<Vec<Backup>> {
+    let dir = vfs::open_dir(&format!("/{}/{}", our.package_id(), DRIVE), false, None)?;
+    let mut backups: Vec<Backup> = dir
+        .read()?
+        .into_iter()
+        .filter_map(|entry| {
+            let name = entry.path.rsplit('/').next()?.to_string();
+            let created = name
+                .strip_prefix("backup-")?
+                .strip_suffix(".bin")?
+                .parse()
+                .ok()?;
+            Some(Backup { name, created })
+        })
+        .collect();
+    backups.sort_by_key(|backup| backup.created);
+    Ok(backups)
+}
+
+/// Seconds until the next scheduled backup is due: now, if we've never made one.
+pub fn next_due(our: &Address) -> u64 {
+    let last = list(our)
+        .ok()
+        .and_then(|backups| backups.last().map(|backup| backup.created));
+    match last {
+        Some(last) => (last + INTERVAL).saturating_sub(crate::now()),
+        None => 0,
+    }
+}
+
+/// Write the archive and delete the oldest backups past `MAX_BACKUPS`.
+/// Returns the new backup's name.
+pub fn save(our: &Address, archive: &Archive) -> anyhow::Result<String> {
+    let name = format!("backup-{}.bin", archive.created);
+    vfs::create_file(&path(our, &name), None)?.write(&bincode::serialize(archive)?)?;
+    let backups = list(our)?;
+    let excess = backups.len().saturating_sub(MAX_BACKUPS);
+    for backup in &backups[..excess] {
+        if let Err(e) = vfs::remove_file(&path(our, &backup.name), None) {
+            println!("failed to remove backup {}: {:?}", backup.name, e);
+        }
+    }
+    Ok(name)
+}
+
+pub fn load(our: &Address, name: &str) -> anyhow::Result<Archive> {
+    // only ever read files we listed, never an arbitrary path
+    if !list(our)?.iter().any(|backup| backup.name == name) {
+        return Err(UpdateError::not_found("backup", name).into());
+    }
+    let bytes = vfs::open_file(&path(our, name), false, None)?.read()?;
+    Ok(bincode::deserialize(&bytes)?)
+}
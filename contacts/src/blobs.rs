@@ -0,0 +1,66 @@
+//! Content-addressed storage for avatars and attachments in the package VFS drive.
+//! Contacts only hold the hash of each file, so the bytes themselves are never
+//! part of the automerge document and are fetched from peers when missing.
+use kinode_process_lib::{vfs, Address};
+use sha2::{Digest, Sha256};
+
+const DRIVE: &str = "blobs";
+
+pub fn create_drive(our: &Address) -> anyhow::Result<()> {
+    vfs::create_drive(our.package_id(), DRIVE, None)?;
+    Ok(())
+}
+
+pub fn hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Whether `hash` looks like one made by `hash`. Hashes come from peers and
+/// become part of a VFS path, so anything else must never reach `path`.
+pub fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+fn path(our: &Address, hash: &str) -> String {
+    format!("/{}/{}/{}", our.package_id(), DRIVE, hash)
+}
+
+pub fn has(our: &Address, hash: &str) -> bool {
+    is_valid_hash(hash) && vfs::open_file(&path(our, hash), false, None).is_ok()
+}
+
+pub fn load(our: &Address, hash: &str) -> Option<Vec<u8>> {
+    if !is_valid_hash(hash) {
+        return None;
+    }
+    vfs::open_file(&path(our, hash), false, None)
+        .and_then(|file| file.read())
+        .ok()
+}
+
+/// Store the bytes under their hash and return it. Writing the same bytes
+/// twice is harmless.
+pub fn save(our: &Address, bytes: &[u8]) -> anyhow::Result<String> {
+    let hash = hash(bytes);
+    if !has(our, &hash) {
+        vfs::create_file(&path(our, &hash), None)?.write(bytes)?;
+    }
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_our_hashes_are_valid() {
+        assert!(is_valid_hash(&hash(b"avatar")));
+        assert!(!is_valid_hash(&hash(b"avatar").to_uppercase()));
+        assert!(!is_valid_hash(""));
+        assert!(!is_valid_hash("../books/book.automerge"));
+        assert!(!is_valid_hash(&format!("../{}", &hash(b"avatar")[3..])));
+    }
+}
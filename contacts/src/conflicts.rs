@@ -0,0 +1,171 @@
+//! When two peers set the same field concurrently, automerge keeps every
+//! value and hydration silently picks one of them. Here we look for those
+//! conflicting values directly in the document so they can be shown to the
+//! user, and resolve them with a write that supersedes all of them.
+use crate::response::UpdateError;
+use automerge::{
+    transaction::Transactable, AutoCommit, ObjId, ObjType, ReadDoc, ScalarValue, Value,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ConflictField {
+    Description,
+    /// The key of the social
+    Social(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Conflict {
+    pub field: ConflictField,
+    /// Every concurrently written value.
+    pub values: Vec<String>,
+}
+
+/// All conflicting contact fields in a book, keyed by contact ID.
+pub fn find(doc: &AutoCommit) -> anyhow::Result<BTreeMap<String, Vec<Conflict>>> {
+    let mut conflicts = BTreeMap::new();
+    let Some((_, contacts)) = doc.get(automerge::ROOT, "contacts")? else {
+        return Ok(conflicts);
+    };
+    for id in doc.keys(&contacts) {
+        let Some((_, contact)) = doc.get(&contacts, id.as_str())? else {
+            continue;
+        };
+        let mut found = vec![];
+        if let Some(values) = conflicting_values(doc, &contact, "description")? {
+            found.push(Conflict {
+                field: ConflictField::Description,
+                values,
+            });
+        }
+        if let Some((_, socials)) = doc.get(&contact, "socials")? {
+            for key in doc.keys(&socials) {
+                if let Some(values) = conflicting_values(doc, &socials, &key)? {
+                    found.push(Conflict {
+                        field: ConflictField::Social(key),
+                        values,
+                    });
+                }
+            }
+        }
+        if !found.is_empty() {
+            conflicts.insert(id, found);
+        }
+    }
+    Ok(conflicts)
+}
+
+/// Overwrite a conflicting field with `value`. A single new write
+/// supersedes all of the concurrent ones, which clears the conflict.
+pub fn resolve(
+    doc: &mut AutoCommit,
+    contact_id: &str,
+    field: &ConflictField,
+    value: &str,
+) -> anyhow::Result<()> {
+    let Some((_, contacts)) = doc.get(automerge::ROOT, "contacts")? else {
+        return Err(UpdateError::not_found("contact", contact_id).into());
+    };
+    let Some((_, contact)) = doc.get(&contacts, contact_id)? else {
+        return Err(UpdateError::not_found("contact", contact_id).into());
+    };
+    match field {
+        ConflictField::Description => {
+            let text = doc.put_object(&contact, "description", ObjType::Text)?;
+            doc.splice_text(&text, 0, 0, value)?;
+        }
+        ConflictField::Social(key) => {
+            let Some((_, socials)) = doc.get(&contact, "socials")? else {
+                return Err(UpdateError::not_found("social", key).into());
+            };
+            doc.put(&socials, key.as_str(), value)?;
+        }
+    }
+    Ok(())
+}
+
+/// `None` unless more than one distinct value is stored at `prop`. Text is
+/// compared by content.
+fn conflicting_values(
+    doc: &AutoCommit,
+    obj: &ObjId,
+    prop: &str,
+) -> anyhow::Result<Option<Vec<String>>> {
+    let all = doc.get_all(obj, prop)?;
+    if all.len() < 2 {
+        return Ok(None);
+    }
+    let values: Vec<String> = all
+        .into_iter()
+        .map(|(value, id)| match value {
+            Value::Object(ObjType::Text) => Ok(doc.text(&id)?),
+            Value::Scalar(scalar) => Ok(match scalar.as_ref() {
+                ScalarValue::Str(s) => s.to_string(),
+                other => other.to_string(),
+            }),
+            Value::Object(_) => Err(anyhow::anyhow!("unexpected object in contact field")),
+        })
+        .collect::<anyhow::Result<_>>()?;
+    // peers that wrote the same value, say by normalizing the same social,
+    // don't disagree
+    let mut distinct: Vec<String> = vec![];
+    for value in values {
+        if !distinct.contains(&value) {
+            distinct.push(value);
+        }
+    }
+    if distinct.len() < 2 {
+        return Ok(None);
+    }
+    Ok(Some(distinct))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        contact_book::{Contact, ContactBook},
+        testing::our,
+    };
+    use autosurgeon::reconcile;
+
+    /// Our book with `alice` in it, and a copy of it with another actor.
+    fn ours_and_theirs() -> (AutoCommit, AutoCommit) {
+        let mut book = ContactBook::new("Test".to_string(), &our());
+        book.contacts
+            .insert("alice".to_string(), Contact::default());
+        let mut ours = AutoCommit::new();
+        reconcile(&mut ours, &book).unwrap();
+        let theirs = ours.fork();
+        (ours, theirs)
+    }
+
+    fn put_social(doc: &mut AutoCommit, value: &str) {
+        let (_, contacts) = doc.get(automerge::ROOT, "contacts").unwrap().unwrap();
+        let (_, alice) = doc.get(&contacts, "alice").unwrap().unwrap();
+        let (_, socials) = doc.get(&alice, "socials").unwrap().unwrap();
+        doc.put(&socials, "email", value).unwrap();
+    }
+
+    #[test]
+    fn concurrent_values_conflict() {
+        let (mut ours, mut theirs) = ours_and_theirs();
+        put_social(&mut ours, "alice@a.com");
+        put_social(&mut theirs, "alice@b.com");
+        ours.merge(&mut theirs).unwrap();
+        let conflicts = find(&ours).unwrap();
+        assert_eq!(conflicts["alice"].len(), 1);
+        assert_eq!(conflicts["alice"][0].values.len(), 2);
+    }
+
+    #[test]
+    fn identical_values_do_not_conflict() {
+        let (mut ours, mut theirs) = ours_and_theirs();
+        put_social(&mut ours, "alice@a.com");
+        put_social(&mut theirs, "alice@a.com");
+        ours.merge(&mut theirs).unwrap();
+        assert!(find(&ours).unwrap().is_empty());
+    }
+}
@@ -1,8 +1,8 @@
-use crate::request::Update;
-use autosurgeon::{Hydrate, Reconcile};
+use crate::{blobs, conflicts::ConflictField, request::Update, response::UpdateError, socials};
+use autosurgeon::{Hydrate, Reconcile, Text};
 use kinode_process_lib::Address;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 /// A "rolodex". A collection of contacts and peers that can make changes.
 /// The owner is the user that originally created the book. Only they
@@ -10,14 +10,155 @@ use std::collections::BTreeMap;
 /// The owner cannot change or be removed from the peers list.
 #[derive(Debug, Clone, Reconcile, Hydrate, Serialize, Deserialize)]
 pub struct ContactBook {
+    /// See `migrations::SCHEMA_VERSION`.
+    #[autosurgeon(missing = "Default::default")]
+    #[serde(default)]
+    pub schema_version: u64,
     pub name: String,
     #[autosurgeon(with = "autosurgeon_address")]
     pub owner: Address,
+    /// What the book is for, shown alongside its name.
+    pub description: Option<String>,
+    /// A CSS hex color such as `#4a90e2`, used to tell books apart in the UI.
+    pub color: Option<String>,
+    /// Short text, typically an emoji, shown next to the name.
+    pub icon: Option<String>,
+    /// The status new peers are invited with unless another is chosen.
+    #[autosurgeon(missing = "default_peer_status")]
+    #[serde(default = "default_peer_status")]
+    pub default_peer_status: PeerStatus,
     /// The contacts in the address book.
     pub contacts: BTreeMap<String, Contact>,
     /// The peers that have a copy of the address book and can make changes.
     /// keys are addresses.to_string()
     pub peers: BTreeMap<String, PeerStatus>,
+    /// Companies and other groups that contacts can belong to.
+    #[autosurgeon(missing = "Default::default")]
+    #[serde(default)]
+    pub organizations: BTreeMap<String, Organization>,
+    /// The ordered stages of this book's sales pipeline.
+    #[autosurgeon(missing = "default_deal_stages")]
+    #[serde(default = "default_deal_stages")]
+    pub deal_stages: Vec<String>,
+    /// Open and closed deals, keyed by deal ID.
+    #[autosurgeon(missing = "Default::default")]
+    #[serde(default)]
+    pub deals: BTreeMap<String, Deal>,
+    /// Follow-up tasks attached to contacts, keyed by task ID.
+    #[autosurgeon(missing = "Default::default")]
+    #[serde(default)]
+    pub tasks: BTreeMap<String, Task>,
+    /// Directed, typed links between contacts, keyed by `Relationship::key`
+    /// so the same link can't be added twice.
+    #[autosurgeon(missing = "Default::default")]
+    #[serde(default)]
+    pub relationships: BTreeMap<String, Relationship>,
+    /// Removed contacts, kept until `TRASH_RETENTION` has passed so they can
+    /// be restored. Keyed by contact ID.
+    #[autosurgeon(missing = "Default::default")]
+    #[serde(default)]
+    pub trash: BTreeMap<String, TrashedContact>,
+}
+
+/// How long removed contacts stay in the trash, in seconds.
+pub const TRASH_RETENTION: u64 = 30 * 24 * 60 * 60;
+
+/// Longest name or ID of a contact, organization, deal, task or book,
+/// and longest title, stage, social, or other short text field.
+pub const MAX_NAME_LEN: usize = 256;
+/// Longest contact description, organization notes, or book description.
+pub const MAX_DESCRIPTION_LEN: usize = 10_000;
+/// Longest book icon: a few emoji, not a picture.
+pub const MAX_ICON_LEN: usize = 16;
+
+/// Names and IDs must be non-empty and at most `MAX_NAME_LEN` characters.
+pub fn check_id(field: &str, id: &str) -> Result<(), UpdateError> {
+    if id.trim().is_empty() {
+        return Err(UpdateError::EmptyId {
+            field: field.to_string(),
+        });
+    }
+    check_len(field, id, MAX_NAME_LEN)
+}
+
+fn check_len(field: &str, value: &str, max: usize) -> Result<(), UpdateError> {
+    if value.chars().count() > max {
+        return Err(UpdateError::TooLong {
+            field: field.to_string(),
+            max,
+        });
+    }
+    Ok(())
+}
+
+/// The byte offset of the `pos`th character of `text`, or its length if it
+/// has no more than `pos` characters. Updates count characters, but `Text`
+/// splices its cached string, and so automerge's, at byte offsets.
+fn byte_offset(text: &str, pos: usize) -> usize {
+    text.char_indices()
+        .nth(pos)
+        .map_or(text.len(), |(offset, _)| offset)
+}
+
+/// Blob hashes end up in VFS paths, on our node and every peer's.
+fn check_hash(hash: &str) -> Result<(), UpdateError> {
+    if !blobs::is_valid_hash(hash) {
+        return Err(UpdateError::invalid("hash", "not a SHA-256 hash"));
+    }
+    Ok(())
+}
+
+fn default_peer_status() -> PeerStatus {
+    PeerStatus::ReadWrite
+}
+
+/// Everything about a book that only its owner can change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookSettings {
+    pub name: String,
+    pub description: Option<String>,
+    pub color: Option<String>,
+    pub icon: Option<String>,
+    pub default_peer_status: PeerStatus,
+}
+
+fn normalize_socials(
+    socials: BTreeMap<String, String>,
+) -> Result<BTreeMap<String, String>, UpdateError> {
+    for (key, value) in &socials {
+        check_len("social name", key, MAX_NAME_LEN)?;
+        check_len("social value", value, MAX_NAME_LEN)?;
+    }
+    socials::normalize_all(socials)
+}
+
+/// For every entry of `current` that differs from `before`, put back its
+/// `before` value, or remove it if it didn't exist. Entries that are the
+/// same are left untouched. `restore` builds the restored value from the
+/// old one and the one it replaces.
+fn revert_entries<T: Serialize>(
+    current: &mut BTreeMap<String, T>,
+    mut before: BTreeMap<String, T>,
+    restore: impl Fn(T, Option<T>) -> T,
+) {
+    let keys: BTreeSet<String> = before.keys().chain(current.keys()).cloned().collect();
+    for key in keys {
+        let old = before.remove(&key);
+        if serde_json::to_value(&old).ok() == serde_json::to_value(current.get(&key)).ok() {
+            continue;
+        }
+        let existing = current.remove(&key);
+        if let Some(old) = old {
+            current.insert(key, restore(old, existing));
+        }
+    }
+}
+
+fn default_deal_stages() -> Vec<String> {
+    ["Lead", "Qualified", "Proposal", "Won", "Lost"]
+        .into_iter()
+        .map(String::from)
+        .collect()
 }
 
 #[derive(Debug, Default, Clone, Reconcile, Hydrate, Serialize, Deserialize, PartialEq)]
@@ -30,47 +171,864 @@ pub enum PeerStatus {
 
 #[derive(Debug, Default, Clone, Reconcile, Hydrate, Serialize, Deserialize)]
 pub struct Contact {
-    pub description: Option<String>,
+    /// Stored as an automerge Text object so that concurrent edits from
+    /// different peers interleave rather than overwrite one another.
+    #[serde(default, with = "serde_description")]
+    pub description: Option<Text>,
+    pub socials: BTreeMap<String, String>,
+    /// Key of the organization in the book this contact belongs to, if any.
+    pub organization: Option<String>,
+    /// The contact's Kinode node name, e.g. `doria.os`, if they have one.
+    pub node: Option<String>,
+    /// Hash of the avatar image in the blob store.
+    pub avatar: Option<String>,
+    /// Files attached to this contact, keyed by their hash in the blob store.
+    #[autosurgeon(missing = "Default::default")]
+    #[serde(default)]
+    pub attachments: BTreeMap<String, Attachment>,
+    /// Duplicates that were merged into this contact.
+    #[autosurgeon(missing = "Default::default")]
+    #[serde(default)]
+    pub merged_from: Vec<MergeRecord>,
+}
+
+#[derive(Debug, Clone, Reconcile, Hydrate, Serialize, Deserialize)]
+pub struct MergeRecord {
+    /// ID the merged contact had.
+    pub id: String,
+    #[autosurgeon(with = "autosurgeon_address")]
+    pub by: Address,
+    /// In seconds since the unix epoch.
+    pub at: u64,
+}
+
+#[derive(Debug, Default, Clone, Reconcile, Hydrate, Serialize, Deserialize)]
+pub struct Attachment {
+    /// Original file name.
+    pub name: String,
+    pub mime: Option<String>,
+    pub size: u64,
+}
+
+/// Check that `node` is a valid Kinode node name and normalize it.
+/// A full address such as `doria.os@contacts:crdt-crm:mothu-et-doria.os`
+/// is also accepted, in which case only the node is kept.
+pub fn parse_node_id(node: &str) -> anyhow::Result<String> {
+    let node = node.trim().to_lowercase();
+    let node = match node.parse::<Address>() {
+        Ok(address) => address.node,
+        Err(_) => node,
+    };
+    let valid_chars = node
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+    let valid_labels = node.split('.').count() > 1 && node.split('.').all(|l| !l.is_empty());
+    if !valid_chars || !valid_labels {
+        return Err(anyhow::anyhow!("invalid node id: {}", node));
+    }
+    Ok(node)
+}
+
+impl Contact {
+    /// Check field lengths and normalize the node and socials of a contact
+    /// being added.
+    fn validate(&mut self) -> Result<(), UpdateError> {
+        if let Some(description) = &self.description {
+            check_len("description", description.as_str(), MAX_DESCRIPTION_LEN)?;
+        }
+        self.node = self
+            .node
+            .as_deref()
+            .map(parse_node_id)
+            .transpose()
+            .map_err(|e| UpdateError::invalid("node", e))?;
+        self.socials = normalize_socials(std::mem::take(&mut self.socials))?;
+        for attachment in self.attachments.values() {
+            check_id("attachment name", &attachment.name)?;
+        }
+        for hash in self.blob_hashes() {
+            check_hash(hash)?;
+        }
+        Ok(())
+    }
+
+    /// Every blob this contact refers to.
+    pub fn blob_hashes(&self) -> impl Iterator<Item = &String> {
+        self.avatar.iter().chain(self.attachments.keys())
+    }
+
+    /// A hydrated `Text` only reconciles the edits made to it since, so a
+    /// contact being moved elsewhere in the document needs its description
+    /// written out afresh or it would arrive empty.
+    fn detached(mut self) -> Self {
+        self.description = self.description.map(|text| Text::with_value(text.as_str()));
+        self
+    }
+
+    /// This contact, from another version of the book, in place of `current`.
+    /// The current description object is kept, so that the change to it is
+    /// an edit rather than a replacement.
+    fn replacing(mut self, current: Option<Contact>) -> Self {
+        let Some(mut text) = current.and_then(|c| c.description) else {
+            return self.detached();
+        };
+        match self.description.take() {
+            Some(description) => {
+                text.update(description.as_str());
+                self.description = Some(text);
+            }
+            None => self.description = None,
+        }
+        self
+    }
+}
+
+#[derive(Debug, Default, Clone, Reconcile, Hydrate, Serialize, Deserialize)]
+pub struct Organization {
+    pub name: String,
+    pub domain: Option<String>,
+    pub notes: Option<String>,
     pub socials: BTreeMap<String, String>,
 }
 
+impl Organization {
+    fn validate(&mut self) -> Result<(), UpdateError> {
+        check_id("organization name", &self.name)?;
+        if let Some(domain) = &self.domain {
+            check_len("domain", domain, MAX_NAME_LEN)?;
+        }
+        if let Some(notes) = &self.notes {
+            check_len("notes", notes, MAX_DESCRIPTION_LEN)?;
+        }
+        self.socials = normalize_socials(std::mem::take(&mut self.socials))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Reconcile, Hydrate, Serialize, Deserialize)]
+pub struct Deal {
+    pub title: String,
+    /// In the smallest unit of the currency, e.g. cents.
+    pub value: u64,
+    pub currency: String,
+    /// Must be one of the book's `deal_stages`.
+    pub stage: String,
+    /// The peer responsible for the deal.
+    #[autosurgeon(with = "autosurgeon_address")]
+    pub owner: Address,
+    /// IDs of the contacts involved in the deal.
+    pub contacts: Vec<String>,
+    /// Expected or actual close date, in seconds since the unix epoch.
+    pub close_date: Option<u64>,
+}
+
+#[derive(Debug, Clone, Reconcile, Hydrate, Serialize, Deserialize)]
+pub struct Task {
+    /// ID of the contact this task is about.
+    pub contact: String,
+    pub title: String,
+    /// In seconds since the unix epoch. A reminder is sent when this passes.
+    pub due: u64,
+    #[autosurgeon(with = "autosurgeon_address")]
+    pub assignee: Address,
+    pub done: bool,
+}
+
+/// `from` is `kind` `to`, e.g. Alice ReportsTo Bob.
+#[derive(Debug, Clone, Reconcile, Hydrate, Serialize, Deserialize, PartialEq)]
+pub struct Relationship {
+    pub from: String,
+    pub to: String,
+    pub kind: RelationshipKind,
+}
+
+#[derive(Debug, Clone, Reconcile, Hydrate, Serialize, Deserialize, PartialEq)]
+pub enum RelationshipKind {
+    IntroducedBy,
+    ReportsTo,
+    SpouseOf,
+    Other(String),
+}
+
+impl Relationship {
+    pub fn key(&self) -> String {
+        serde_json::to_string(&(&self.from, &self.kind, &self.to)).unwrap()
+    }
+}
+
+/// The relationships a contact takes part in, in either direction.
+#[derive(Debug, Serialize)]
+pub struct Neighborhood<'a> {
+    pub outgoing: Vec<&'a Relationship>,
+    pub incoming: Vec<&'a Relationship>,
+}
+
+/// A removed contact, along with everything that referred to it, so that
+/// restoring it puts the book back the way it was.
+#[derive(Debug, Clone, Reconcile, Hydrate, Serialize, Deserialize)]
+pub struct TrashedContact {
+    pub contact: Contact,
+    #[autosurgeon(with = "autosurgeon_address")]
+    pub deleted_by: Address,
+    /// In seconds since the unix epoch.
+    pub deleted_at: u64,
+    /// IDs of the deals the contact was part of.
+    pub deals: Vec<String>,
+    pub tasks: BTreeMap<String, Task>,
+    pub relationships: BTreeMap<String, Relationship>,
+}
+
+/// One stage of the pipeline summary: how many deals are in it and their total
+/// value per currency.
+#[derive(Debug, Serialize)]
+pub struct PipelineStage {
+    pub stage: String,
+    pub deals: usize,
+    pub totals: BTreeMap<String, u64>,
+}
+
 impl ContactBook {
     pub fn new(name: String, owner: &Address) -> Self {
         Self {
+            schema_version: crate::migrations::SCHEMA_VERSION,
             name,
             owner: owner.clone(),
+            description: None,
+            color: None,
+            icon: None,
+            default_peer_status: default_peer_status(),
             contacts: BTreeMap::new(),
             peers: BTreeMap::from([(owner.to_string(), PeerStatus::Owner)]),
+            organizations: BTreeMap::new(),
+            deal_stages: default_deal_stages(),
+            deals: BTreeMap::new(),
+            tasks: BTreeMap::new(),
+            relationships: BTreeMap::new(),
+            trash: BTreeMap::new(),
         }
     }
 
-    pub fn apply_update(&mut self, update: Update) -> anyhow::Result<()> {
+    /// Permanently delete contacts that have been in the trash for longer
+    /// than `TRASH_RETENTION`.
+    pub fn purge_trash(&mut self, now: u64) {
+        self.trash
+            .retain(|_, trashed| trashed.deleted_at + TRASH_RETENTION > now);
+    }
+
+    /// Whether `purge_trash` would delete anything.
+    pub fn has_expired_trash(&self, now: u64) -> bool {
+        self.trash
+            .values()
+            .any(|trashed| trashed.deleted_at + TRASH_RETENTION <= now)
+    }
+
+    pub fn references_blob(&self, hash: &str) -> bool {
+        self.contacts
+            .values()
+            .any(|c| c.blob_hashes().any(|h| h == hash))
+    }
+
+    pub fn neighborhood(&self, id: &str) -> Neighborhood<'_> {
+        Neighborhood {
+            outgoing: self
+                .relationships
+                .values()
+                .filter(|r| r.from == id)
+                .collect(),
+            incoming: self.relationships.values().filter(|r| r.to == id).collect(),
+        }
+    }
+
+    /// Fold contact `merge_id` into `keep_id`. Fields `keep_id` is missing are
+    /// taken from the other contact, socials are unioned (keeping both values
+    /// under different keys when they differ), and everything that referred to
+    /// `merge_id` now refers to `keep_id`. `merge_id` itself is moved to the
+    /// trash, in case the merge was a mistake.
+    fn merge_contacts(
+        &mut self,
+        author: &Address,
+        keep_id: &str,
+        merge_id: &str,
+    ) -> Result<(), UpdateError> {
+        if keep_id == merge_id {
+            return Err(UpdateError::invalid(
+                "merge",
+                "cannot merge a contact into itself",
+            ));
+        }
+        let keep = self
+            .contacts
+            .get(keep_id)
+            .ok_or(UpdateError::not_found("contact", keep_id))?;
+        let merged = self
+            .contacts
+            .get(merge_id)
+            .ok_or(UpdateError::not_found("contact", merge_id))?;
+        // the merged contact goes to the trash, see `RemoveContact`
+        if self.trash.contains_key(merge_id) {
+            return Err(UpdateError::Duplicate {
+                kind: "trashed contact".to_string(),
+                id: merge_id.to_string(),
+            });
+        }
+        let mut socials = keep.socials.clone();
+        for (key, value) in &merged.socials {
+            let (mut key, value) =
+                socials::normalize(key, value).map_err(|e| UpdateError::invalid("social", e))?;
+            if socials.get(&key) == Some(&value) {
+                continue;
+            }
+            while socials.contains_key(&key) {
+                key = format!("{} ({})", key, merge_id);
+            }
+            check_len("social name", &key, MAX_NAME_LEN)?;
+            socials.insert(key, value);
+        }
+
+        let merged = self.contacts.remove(merge_id).unwrap().detached();
+        self.trash.insert(
+            merge_id.to_string(),
+            TrashedContact {
+                contact: merged.clone(),
+                deleted_by: author.clone(),
+                deleted_at: crate::now(),
+                // these now belong to the kept contact
+                deals: vec![],
+                tasks: BTreeMap::new(),
+                relationships: BTreeMap::new(),
+            },
+        );
+        let keep = self.contacts.get_mut(keep_id).unwrap();
+        keep.socials = socials;
+        if let Some(other) = merged.description {
+            match &mut keep.description {
+                None => keep.description = Some(other),
+                Some(text) if !other.as_str().is_empty() => {
+                    let end = text.as_str().len();
+                    text.splice(end, 0, format!("\n{}", other.as_str()));
+                }
+                Some(_) => {}
+            }
+        }
+        keep.organization = keep.organization.take().or(merged.organization);
+        keep.node = keep.node.take().or(merged.node);
+        keep.avatar = keep.avatar.take().or(merged.avatar);
+        keep.attachments.extend(merged.attachments);
+        keep.merged_from.extend(merged.merged_from);
+        keep.merged_from.push(MergeRecord {
+            id: merge_id.to_string(),
+            by: author.clone(),
+            at: crate::now(),
+        });
+
+        for deal in self.deals.values_mut() {
+            if deal.contacts.iter().any(|c| c == merge_id) {
+                deal.contacts.retain(|c| c != merge_id && c != keep_id);
+                deal.contacts.push(keep_id.to_string());
+            }
+        }
+        for task in self.tasks.values_mut() {
+            if task.contact == merge_id {
+                task.contact = keep_id.to_string();
+            }
+        }
+        // relationships are keyed by their endpoints, so re-key the ones that change
+        let (moved, kept): (BTreeMap<_, _>, _) = std::mem::take(&mut self.relationships)
+            .into_iter()
+            .partition(|(_, r)| r.from == merge_id || r.to == merge_id);
+        self.relationships = kept;
+        for (_, mut r) in moved {
+            if r.from == merge_id {
+                r.from = keep_id.to_string();
+            }
+            if r.to == merge_id {
+                r.to = keep_id.to_string();
+            }
+            if r.from != r.to {
+                self.relationships.insert(r.key(), r);
+            }
+        }
+        Ok(())
+    }
+
+    /// Tasks that are not done and whose due date is before `now`.
+    pub fn overdue_tasks(&self, now: u64) -> BTreeMap<&String, &Task> {
+        self.tasks
+            .iter()
+            .filter(|(_, task)| !task.done && task.due < now)
+            .collect()
+    }
+
+    /// Summary of deals in each stage, in stage order.
+    pub fn pipeline(&self) -> Vec<PipelineStage> {
+        self.deal_stages
+            .iter()
+            .map(|stage| {
+                let mut summary = PipelineStage {
+                    stage: stage.clone(),
+                    deals: 0,
+                    totals: BTreeMap::new(),
+                };
+                for deal in self.deals.values().filter(|d| &d.stage == stage) {
+                    summary.deals += 1;
+                    *summary.totals.entry(deal.currency.clone()).or_default() += deal.value;
+                }
+                summary
+            })
+            .collect()
+    }
+
+    fn check_deal(&self, deal: &Deal) -> Result<(), UpdateError> {
+        check_id("deal title", &deal.title)?;
+        check_len("currency", &deal.currency, MAX_NAME_LEN)?;
+        if !self.deal_stages.contains(&deal.stage) {
+            return Err(UpdateError::not_found("stage", &deal.stage));
+        }
+        if !self.peers.contains_key(&deal.owner.to_string()) {
+            return Err(UpdateError::not_found("peer", &deal.owner.to_string()));
+        }
+        if let Some(id) = deal
+            .contacts
+            .iter()
+            .find(|id| !self.contacts.contains_key(*id))
+        {
+            return Err(UpdateError::not_found("contact", id));
+        }
+        Ok(())
+    }
+
+    /// Map of organization key to the IDs of the contacts that belong to it.
+    /// Every organization is present, even those with no contacts.
+    pub fn contacts_by_organization(&self) -> BTreeMap<String, Vec<String>> {
+        let mut map: BTreeMap<String, Vec<String>> = self
+            .organizations
+            .keys()
+            .map(|id| (id.clone(), vec![]))
+            .collect();
+        for (id, contact) in &self.contacts {
+            if let Some(org_id) = &contact.organization
+                && let Some(members) = map.get_mut(org_id)
+            {
+                members.push(id.clone());
+            }
+        }
+        map
+    }
+
+    /// Put back what differs from `before`, an earlier version of this book,
+    /// as edited by `author`. Only entries that differ are touched, and
+    /// contact descriptions are kept as the same text, so that reconciling
+    /// the result writes no more than the difference. Peers and ownership
+    /// are never reverted, nor are the book's settings unless `author` is
+    /// still its owner. See `history::revert`.
+    pub fn revert_to(&mut self, author: &Address, before: ContactBook) {
+        if author == &self.owner {
+            self.name = before.name;
+            self.description = before.description;
+            self.color = before.color;
+            self.icon = before.icon;
+            self.default_peer_status = before.default_peer_status;
+        }
+        self.deal_stages = before.deal_stages;
+        revert_entries(&mut self.contacts, before.contacts, Contact::replacing);
+        revert_entries(&mut self.trash, before.trash, |old, existing| {
+            TrashedContact {
+                contact: old
+                    .contact
+                    .replacing(existing.map(|trashed| trashed.contact)),
+                ..old
+            }
+        });
+        revert_entries(&mut self.organizations, before.organizations, |old, _| old);
+        revert_entries(&mut self.deals, before.deals, |old, _| old);
+        revert_entries(&mut self.tasks, before.tasks, |old, _| old);
+        revert_entries(&mut self.relationships, before.relationships, |old, _| old);
+    }
+
+    /// Apply an update made by `author`, one of the book's peers.
+    /// Fails, leaving the book unchanged, if the update is invalid.
+    pub fn apply_update(&mut self, author: &Address, update: Update) -> Result<(), UpdateError> {
         match update {
-            Update::AddContact(id, contact) => {
+            Update::AddContact(id, mut contact) => {
+                check_id("contact name", &id)?;
+                if self.contacts.contains_key(&id) {
+                    return Err(UpdateError::Duplicate {
+                        kind: "contact".to_string(),
+                        id,
+                    });
+                }
+                contact.validate()?;
                 self.contacts.insert(id, contact);
             }
             Update::RemoveContact(id) => {
-                self.contacts
+                // an earlier contact of the same name that is still in the
+                // trash must be restored or deleted from the trash first, or
+                // it would be lost
+                if self.trash.contains_key(&id) {
+                    return Err(UpdateError::Duplicate {
+                        kind: "trashed contact".to_string(),
+                        id,
+                    });
+                }
+                let contact = self
+                    .contacts
+                    .remove(&id)
+                    .ok_or(UpdateError::not_found("contact", &id))?;
+                let mut deals = vec![];
+                for (deal_id, deal) in self.deals.iter_mut() {
+                    if deal.contacts.contains(&id) {
+                        deal.contacts.retain(|c| c != &id);
+                        deals.push(deal_id.clone());
+                    }
+                }
+                let (tasks, kept) = std::mem::take(&mut self.tasks)
+                    .into_iter()
+                    .partition(|(_, task)| task.contact == id);
+                self.tasks = kept;
+                let (relationships, kept) = std::mem::take(&mut self.relationships)
+                    .into_iter()
+                    .partition(|(_, r)| r.from == id || r.to == id);
+                self.relationships = kept;
+                self.trash.insert(
+                    id,
+                    TrashedContact {
+                        contact: contact.detached(),
+                        deleted_by: author.clone(),
+                        deleted_at: crate::now(),
+                        deals,
+                        tasks,
+                        relationships,
+                    },
+                );
+            }
+            Update::MergeContacts(keep_id, merge_id) => {
+                self.merge_contacts(author, &keep_id, &merge_id)?;
+            }
+            Update::RestoreContact(id) => {
+                if self.contacts.contains_key(&id) {
+                    return Err(UpdateError::Duplicate {
+                        kind: "contact".to_string(),
+                        id,
+                    });
+                }
+                let trashed = self
+                    .trash
+                    .remove(&id)
+                    .ok_or(UpdateError::not_found("trashed contact", &id))?;
+                for deal_id in trashed.deals {
+                    if let Some(deal) = self.deals.get_mut(&deal_id) {
+                        deal.contacts.push(id.clone());
+                    }
+                }
+                self.tasks.extend(trashed.tasks);
+                // the other end of a relationship may have been removed in the meantime
+                for (key, r) in trashed.relationships {
+                    let other = if r.from == id { &r.to } else { &r.from };
+                    if self.contacts.contains_key(other) || other == &id {
+                        self.relationships.insert(key, r);
+                    }
+                }
+                self.contacts.insert(id, trashed.contact.detached());
+            }
+            Update::DeleteFromTrash(id) => {
+                self.trash
                     .remove(&id)
-                    .ok_or(anyhow::anyhow!("contact not found"))?;
+                    .ok_or(UpdateError::not_found("trashed contact", &id))?;
+            }
+            Update::RestoreContactVersion(..) => {
+                // needs the book's history, see `history::resolve_versions`
+                return Err(UpdateError::invalid(
+                    "update",
+                    "past versions must be read before the update is applied",
+                ));
+            }
+            Update::PutContact(id, mut contact) => {
+                contact.validate()?;
+                // bring back its deals, tasks and relationships too
+                if self.trash.contains_key(&id) {
+                    self.apply_update(author, Update::RestoreContact(id.clone()))?;
+                }
+                let current = self.contacts.remove(&id);
+                self.contacts.insert(id, contact.replacing(current));
             }
             Update::EditContactDescription(id, description) => {
+                check_len("description", &description, MAX_DESCRIPTION_LEN)?;
                 self.contacts
                     .get_mut(&id)
-                    .map(|c| c.description = Some(description))
-                    .ok_or(anyhow::anyhow!("contact not found"))?;
+                    .map(|c| match &mut c.description {
+                        // diff against the current value so that the edit
+                        // becomes a set of splices rather than a replacement
+                        Some(text) => text.update(description),
+                        None => c.description = Some(Text::with_value(description)),
+                    })
+                    .ok_or(UpdateError::not_found("contact", &id))?;
+            }
+            Update::SpliceContactDescription(id, pos, del, insert) => {
+                let contact = self
+                    .contacts
+                    .get_mut(&id)
+                    .ok_or(UpdateError::not_found("contact", &id))?;
+                let text = contact.description.get_or_insert_with(Text::default);
+                let len = text.as_str().chars().count();
+                if !matches!(pos.checked_add(del), Some(end) if end <= len) {
+                    return Err(UpdateError::invalid("splice", "out of bounds"));
+                }
+                if len - del + insert.chars().count() > MAX_DESCRIPTION_LEN {
+                    return Err(UpdateError::TooLong {
+                        field: "description".to_string(),
+                        max: MAX_DESCRIPTION_LEN,
+                    });
+                }
+                let start = byte_offset(text.as_str(), pos);
+                let end = byte_offset(text.as_str(), pos + del);
+                text.splice(start, (end - start) as isize, insert);
+            }
+            Update::ResolveConflict(id, field, value) => {
+                // the write that clears the conflict is made directly on the document
+                // in `handle_update`, this just keeps the hydrated book in step
+                let contact = self
+                    .contacts
+                    .get_mut(&id)
+                    .ok_or(UpdateError::not_found("contact", &id))?;
+                match field {
+                    ConflictField::Description => match &mut contact.description {
+                        Some(text) => text.update(value),
+                        None => contact.description = Some(Text::with_value(value)),
+                    },
+                    ConflictField::Social(key) => {
+                        contact.socials.insert(key, value);
+                    }
+                }
             }
             Update::EditContactSocial(id, key, value) => {
-                self.contacts
+                let contact = self
+                    .contacts
                     .get_mut(&id)
-                    .map(|c| c.socials.insert(key, value))
-                    .ok_or(anyhow::anyhow!("contact not found"))?;
+                    .ok_or(UpdateError::not_found("contact", &id))?;
+                check_len("social name", &key, MAX_NAME_LEN)?;
+                check_len("social value", &value, MAX_NAME_LEN)?;
+                let (normalized_key, value) = socials::normalize(&key, &value)
+                    .map_err(|e| UpdateError::invalid("social", e))?;
+                // editing a social stored under a non-canonical key moves it,
+                // unless the canonical key holds another value, which is then
+                // left alone as `migrations::normalize_socials` does
+                let key = match normalized_key != key
+                    && contact.socials.contains_key(&key)
+                    && contact.socials.contains_key(&normalized_key)
+                {
+                    true => key,
+                    false => {
+                        contact.socials.remove(&key);
+                        normalized_key
+                    }
+                };
+                contact.socials.insert(key, value);
             }
             Update::RemoveContactSocial(id, key) => {
+                let contact = self
+                    .contacts
+                    .get_mut(&id)
+                    .ok_or(UpdateError::not_found("contact", &id))?;
+                if contact.socials.remove(&key).is_none() {
+                    contact.socials.remove(&socials::normalize_key(&key));
+                }
+            }
+            Update::SetContactNode(id, node) => {
+                let node = node
+                    .as_deref()
+                    .map(parse_node_id)
+                    .transpose()
+                    .map_err(|e| UpdateError::invalid("node", e))?;
+                self.contacts
+                    .get_mut(&id)
+                    .map(|c| c.node = node)
+                    .ok_or(UpdateError::not_found("contact", &id))?;
+            }
+            Update::SetContactAvatar(id, hash) => {
+                if let Some(hash) = &hash {
+                    check_hash(hash)?;
+                }
+                self.contacts
+                    .get_mut(&id)
+                    .map(|c| c.avatar = hash)
+                    .ok_or(UpdateError::not_found("contact", &id))?;
+            }
+            Update::AddContactAttachment(id, hash, attachment) => {
+                check_hash(&hash)?;
+                check_id("attachment name", &attachment.name)?;
+                self.contacts
+                    .get_mut(&id)
+                    .map(|c| c.attachments.insert(hash, attachment))
+                    .ok_or(UpdateError::not_found("contact", &id))?;
+            }
+            Update::RemoveContactAttachment(id, hash) => {
+                self.contacts
+                    .get_mut(&id)
+                    .map(|c| c.attachments.remove(&hash))
+                    .ok_or(UpdateError::not_found("contact", &id))?;
+            }
+            Update::AddOrganization(id, mut organization) => {
+                check_id("organization id", &id)?;
+                if self.organizations.contains_key(&id) {
+                    return Err(UpdateError::Duplicate {
+                        kind: "organization".to_string(),
+                        id,
+                    });
+                }
+                organization.validate()?;
+                self.organizations.insert(id, organization);
+            }
+            Update::EditOrganization(id, mut organization) => {
+                organization.validate()?;
+                self.organizations
+                    .get_mut(&id)
+                    .map(|o| *o = organization)
+                    .ok_or(UpdateError::not_found("organization", &id))?;
+            }
+            Update::RemoveOrganization(id) => {
+                self.organizations
+                    .remove(&id)
+                    .ok_or(UpdateError::not_found("organization", &id))?;
+                for contact in self.contacts.values_mut() {
+                    if contact.organization.as_ref() == Some(&id) {
+                        contact.organization = None;
+                    }
+                }
+            }
+            Update::LinkContactToOrganization(id, org_id) => {
+                if !self.organizations.contains_key(&org_id) {
+                    return Err(UpdateError::not_found("organization", &org_id));
+                }
+                self.contacts
+                    .get_mut(&id)
+                    .map(|c| c.organization = Some(org_id))
+                    .ok_or(UpdateError::not_found("contact", &id))?;
+            }
+            Update::UnlinkContactFromOrganization(id) => {
                 self.contacts
                     .get_mut(&id)
-                    .map(|c| c.socials.remove(&key))
-                    .ok_or(anyhow::anyhow!("contact not found"))?;
+                    .map(|c| c.organization = None)
+                    .ok_or(UpdateError::not_found("contact", &id))?;
+            }
+            Update::SetDealStages(stages) => {
+                for (i, stage) in stages.iter().enumerate() {
+                    check_id("stage", stage)?;
+                    if stages[..i].contains(stage) {
+                        return Err(UpdateError::Duplicate {
+                            kind: "stage".to_string(),
+                            id: stage.clone(),
+                        });
+                    }
+                }
+                if let Some(deal) = self.deals.values().find(|d| !stages.contains(&d.stage)) {
+                    return Err(UpdateError::invalid(
+                        "stages",
+                        format!("stage {} still has deals", deal.stage),
+                    ));
+                }
+                self.deal_stages = stages;
+            }
+            Update::AddDeal(id, deal) => {
+                check_id("deal id", &id)?;
+                if self.deals.contains_key(&id) {
+                    return Err(UpdateError::Duplicate {
+                        kind: "deal".to_string(),
+                        id,
+                    });
+                }
+                self.check_deal(&deal)?;
+                self.deals.insert(id, deal);
+            }
+            Update::MoveDeal(id, stage) => {
+                if !self.deal_stages.contains(&stage) {
+                    return Err(UpdateError::not_found("stage", &stage));
+                }
+                self.deals
+                    .get_mut(&id)
+                    .map(|d| d.stage = stage)
+                    .ok_or(UpdateError::not_found("deal", &id))?;
+            }
+            Update::RemoveDeal(id) => {
+                self.deals
+                    .remove(&id)
+                    .ok_or(UpdateError::not_found("deal", &id))?;
+            }
+            Update::AddTask(id, task) => {
+                check_id("task id", &id)?;
+                check_id("task title", &task.title)?;
+                if self.tasks.contains_key(&id) {
+                    return Err(UpdateError::Duplicate {
+                        kind: "task".to_string(),
+                        id,
+                    });
+                }
+                if !self.contacts.contains_key(&task.contact) {
+                    return Err(UpdateError::not_found("contact", &task.contact));
+                }
+                if !self.peers.contains_key(&task.assignee.to_string()) {
+                    return Err(UpdateError::not_found("peer", &task.assignee.to_string()));
+                }
+                self.tasks.insert(id, task);
+            }
+            Update::SetTaskDone(id, done) => {
+                self.tasks
+                    .get_mut(&id)
+                    .map(|t| t.done = done)
+                    .ok_or(UpdateError::not_found("task", &id))?;
+            }
+            Update::RemoveTask(id) => {
+                self.tasks
+                    .remove(&id)
+                    .ok_or(UpdateError::not_found("task", &id))?;
+            }
+            Update::AddRelationship(relationship) => {
+                for id in [&relationship.from, &relationship.to] {
+                    if !self.contacts.contains_key(id) {
+                        return Err(UpdateError::not_found("contact", id));
+                    }
+                }
+                if let RelationshipKind::Other(kind) = &relationship.kind {
+                    check_id("relationship kind", kind)?;
+                }
+                self.relationships.insert(relationship.key(), relationship);
+            }
+            Update::RemoveRelationship(relationship) => {
+                self.relationships
+                    .remove(&relationship.key())
+                    .ok_or(UpdateError::not_found("relationship", &relationship.key()))?;
+            }
+            Update::PurgeTrash => self.purge_trash(crate::now()),
+            Update::SetBookSettings(settings) => {
+                if author != &self.owner {
+                    return Err(UpdateError::PermissionDenied(
+                        "only the owner can change book settings".to_string(),
+                    ));
+                }
+                check_id("book name", &settings.name)?;
+                if let Some(description) = &settings.description {
+                    check_len("book description", description, MAX_DESCRIPTION_LEN)?;
+                }
+                if let Some(icon) = &settings.icon {
+                    check_len("icon", icon, MAX_ICON_LEN)?;
+                }
+                if let Some(color) = &settings.color
+                    && !(color.len() == 7
+                        && color.starts_with('#')
+                        && color[1..].chars().all(|c| c.is_ascii_hexdigit()))
+                {
+                    return Err(UpdateError::invalid("color", "must be of the form #rrggbb"));
+                }
+                if settings.default_peer_status == PeerStatus::Owner {
+                    return Err(UpdateError::invalid(
+                        "default peer status",
+                        "new peers cannot be owners",
+                    ));
+                }
+                self.name = settings.name;
+                self.description = settings.description;
+                self.color = settings.color;
+                self.icon = settings.icon;
+                self.default_peer_status = settings.default_peer_status;
             }
             Update::AddPeer(address, status) => {
                 self.peers.insert(address.to_string(), status);
@@ -78,13 +1036,55 @@ impl ContactBook {
             Update::RemovePeer(address) => {
                 self.peers
                     .remove(&address.to_string())
-                    .ok_or(anyhow::anyhow!("peer not found"))?;
+                    .ok_or(UpdateError::not_found("peer", &address.to_string()))?;
+            }
+            Update::Batch(updates) => {
+                // work on a copy so that a failure part way leaves us untouched,
+                // but keep going to report every operation that fails
+                let mut book = self.clone();
+                let mut errors = vec![];
+                for (index, update) in updates.into_iter().enumerate() {
+                    if let Update::Batch(_) = update {
+                        errors.push((
+                            index,
+                            UpdateError::invalid("batch", "batches cannot be nested"),
+                        ));
+                        continue;
+                    }
+                    if let Err(e) = book.apply_update(author, update) {
+                        errors.push((index, e));
+                    }
+                }
+                if !errors.is_empty() {
+                    return Err(UpdateError::Batch(errors));
+                }
+                *self = book;
             }
         }
         Ok(())
     }
 }
 
+mod serde_description {
+    use autosurgeon::Text;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    pub(super) fn serialize<S: Serializer>(
+        description: &Option<Text>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        description
+            .as_ref()
+            .map(|text| text.as_str())
+            .serialize(serializer)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Text>, D::Error> {
+        Ok(Option::<String>::deserialize(deserializer)?.map(Text::with_value))
+    }
+}
+
 mod autosurgeon_address {
     use autosurgeon::{Hydrate, HydrateError, Prop, ReadDoc, Reconciler};
     use kinode_process_lib::Address;
@@ -109,3 +1109,296 @@ mod autosurgeon_address {
         reconciler.str(path.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::our;
+    use automerge::AutoCommit;
+    use autosurgeon::{hydrate, reconcile};
+
+    fn book_with_contact(description: &str) -> ContactBook {
+        let mut book = ContactBook::new("Test".to_string(), &our());
+        book.apply_update(
+            &our(),
+            Update::AddContact(
+                "alice".to_string(),
+                Contact {
+                    description: Some(Text::with_value(description)),
+                    ..Default::default()
+                },
+            ),
+        )
+        .unwrap();
+        book
+    }
+
+    fn description(book: &ContactBook, id: &str) -> String {
+        book.contacts[id]
+            .description
+            .as_ref()
+            .map(|text| text.as_str().to_string())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn splice_edits_description() {
+        let mut book = book_with_contact("hello world");
+        book.apply_update(
+            &our(),
+            Update::SpliceContactDescription("alice".to_string(), 6, 5, "there".to_string()),
+        )
+        .unwrap();
+        assert_eq!(description(&book, "alice"), "hello there");
+    }
+
+    /// Apply `update` to `book` as read from a document, and write it back,
+    /// to check that the document's text agrees with the hydrated one.
+    fn apply_to_doc(book: &ContactBook, update: Update) -> (ContactBook, ContactBook) {
+        let mut doc = AutoCommit::new();
+        reconcile(&mut doc, book).unwrap();
+        let mut book: ContactBook = hydrate(&doc).unwrap();
+        book.apply_update(&our(), update).unwrap();
+        reconcile(&mut doc, &book).unwrap();
+        (book, hydrate(&doc).unwrap())
+    }
+
+    #[test]
+    fn splice_counts_characters() {
+        let (book, saved) = apply_to_doc(
+            &book_with_contact("héllo wörld"),
+            Update::SpliceContactDescription("alice".to_string(), 7, 1, "o".to_string()),
+        );
+        assert_eq!(description(&book, "alice"), "héllo world");
+        assert_eq!(description(&saved, "alice"), "héllo world");
+    }
+
+    #[test]
+    fn splice_out_of_bounds_is_rejected() {
+        let mut book = book_with_contact("hello");
+        for (pos, del) in [(usize::MAX, 1), (3, usize::MAX), (6, 0), (2, 4)] {
+            let result = book.apply_update(
+                &our(),
+                Update::SpliceContactDescription("alice".to_string(), pos, del, String::new()),
+            );
+            assert!(matches!(result, Err(UpdateError::Invalid { .. })));
+        }
+        assert_eq!(description(&book, "alice"), "hello");
+    }
+
+    #[test]
+    fn blob_hashes_must_be_sha256() {
+        let mut book = book_with_contact("");
+        let path = "../books/00000000-0000-0000-0000-000000000000.automerge".to_string();
+        let result = book.apply_update(
+            &our(),
+            Update::SetContactAvatar("alice".to_string(), Some(path.clone())),
+        );
+        assert!(matches!(result, Err(UpdateError::Invalid { .. })));
+        let attachment = Attachment {
+            name: "notes.txt".to_string(),
+            mime: None,
+            size: 0,
+        };
+        let result = book.apply_update(
+            &our(),
+            Update::AddContactAttachment("alice".to_string(), path, attachment.clone()),
+        );
+        assert!(matches!(result, Err(UpdateError::Invalid { .. })));
+
+        let hash = blobs::hash(b"notes");
+        book.apply_update(
+            &our(),
+            Update::AddContactAttachment("alice".to_string(), hash.clone(), attachment),
+        )
+        .unwrap();
+        assert!(book.references_blob(&hash));
+    }
+
+    #[test]
+    fn removing_a_contact_never_overwrites_the_trash() {
+        let mut book = book_with_contact("first");
+        let alice = || "alice".to_string();
+        book.apply_update(&our(), Update::RemoveContact(alice()))
+            .unwrap();
+        book.apply_update(&our(), Update::AddContact(alice(), Contact::default()))
+            .unwrap();
+        let result = book.apply_update(&our(), Update::RemoveContact(alice()));
+        assert!(matches!(result, Err(UpdateError::Duplicate { .. })));
+        assert!(book.contacts.contains_key("alice"));
+        let trashed = &book.trash["alice"].contact;
+        assert_eq!(trashed.description.as_ref().unwrap().as_str(), "first");
+        book.apply_update(&our(), Update::DeleteFromTrash(alice()))
+            .unwrap();
+        book.apply_update(&our(), Update::RemoveContact(alice()))
+            .unwrap();
+        assert!(book.contacts.is_empty());
+        let trashed = &book.trash["alice"].contact;
+        assert!(trashed.description.is_none());
+    }
+
+    #[test]
+    fn trash_expires_after_retention() {
+        let mut book = book_with_contact("");
+        book.apply_update(&our(), Update::RemoveContact("alice".to_string()))
+            .unwrap();
+        let deleted_at = book.trash["alice"].deleted_at;
+        assert!(!book.has_expired_trash(deleted_at + TRASH_RETENTION - 1));
+        book.purge_trash(deleted_at + TRASH_RETENTION - 1);
+        assert!(book.trash.contains_key("alice"));
+        assert!(book.has_expired_trash(deleted_at + TRASH_RETENTION));
+        book.purge_trash(deleted_at + TRASH_RETENTION);
+        assert!(book.trash.is_empty());
+    }
+
+    #[test]
+    fn colliding_socials_are_rejected() {
+        let mut book = ContactBook::new("Test".to_string(), &our());
+        let socials = BTreeMap::from([
+            ("twitter".to_string(), "@alice".to_string()),
+            ("X".to_string(), "@not_alice".to_string()),
+        ]);
+        let result = book.apply_update(
+            &our(),
+            Update::AddContact(
+                "alice".to_string(),
+                Contact {
+                    socials,
+                    ..Default::default()
+                },
+            ),
+        );
+        assert!(matches!(result, Err(UpdateError::Duplicate { .. })));
+        assert!(book.contacts.is_empty());
+    }
+
+    #[test]
+    fn editing_a_legacy_social_keeps_the_canonical_one() {
+        let mut book = book_with_contact("");
+        let contact = book.contacts.get_mut("alice").unwrap();
+        contact
+            .socials
+            .insert("twitter".to_string(), "https://x.com/alice".to_string());
+        contact
+            .socials
+            .insert("x".to_string(), "https://x.com/not_alice".to_string());
+        book.apply_update(
+            &our(),
+            Update::EditContactSocial("alice".to_string(), "x".to_string(), "@bob".to_string()),
+        )
+        .unwrap();
+        let socials = &book.contacts["alice"].socials;
+        assert_eq!(socials["twitter"], "https://x.com/alice");
+        assert_eq!(socials["x"], "https://x.com/bob");
+    }
+
+    #[test]
+    fn failed_batch_changes_nothing() {
+        let mut book = book_with_contact("hello");
+        let result = book.apply_update(
+            &our(),
+            Update::Batch(vec![
+                Update::AddContact("bob".to_string(), Contact::default()),
+                Update::SpliceContactDescription("alice".to_string(), 0, 5, "hi".to_string()),
+                Update::SpliceContactDescription("alice".to_string(), 10, 0, "!".to_string()),
+                Update::Batch(vec![]),
+            ]),
+        );
+        let Err(UpdateError::Batch(errors)) = result else {
+            panic!("expected the batch to fail, got {:?}", result);
+        };
+        let failed: Vec<usize> = errors.iter().map(|(index, _)| *index).collect();
+        assert_eq!(failed, vec![2, 3]);
+        assert!(!book.contacts.contains_key("bob"));
+        assert_eq!(description(&book, "alice"), "hello");
+    }
+
+    #[test]
+    fn batch_applies_every_update() {
+        let mut book = book_with_contact("hello");
+        book.apply_update(
+            &our(),
+            Update::Batch(vec![
+                Update::AddContact("bob".to_string(), Contact::default()),
+                Update::SpliceContactDescription("alice".to_string(), 5, 0, "!".to_string()),
+            ]),
+        )
+        .unwrap();
+        assert!(book.contacts.contains_key("bob"));
+        assert_eq!(description(&book, "alice"), "hello!");
+    }
+
+    #[test]
+    fn merging_appends_multibyte_descriptions() {
+        let mut book = book_with_contact("héllo");
+        book.apply_update(
+            &our(),
+            Update::AddContact(
+                "bob".to_string(),
+                Contact {
+                    description: Some(Text::with_value("wörld")),
+                    ..Default::default()
+                },
+            ),
+        )
+        .unwrap();
+        let (book, saved) = apply_to_doc(
+            &book,
+            Update::MergeContacts("alice".to_string(), "bob".to_string()),
+        );
+        assert_eq!(description(&book, "alice"), "héllo\nwörld");
+        assert_eq!(description(&saved, "alice"), "héllo\nwörld");
+    }
+
+    #[test]
+    fn merging_trashes_the_merged_contact() {
+        let mut book = book_with_contact("");
+        let bob = Contact {
+            description: Some(Text::with_value("bob")),
+            socials: BTreeMap::from([("email".to_string(), "bob@example.com".to_string())]),
+            ..Default::default()
+        };
+        book.apply_update(&our(), Update::AddContact("bob".to_string(), bob))
+            .unwrap();
+        book.apply_update(
+            &our(),
+            Update::EditContactSocial(
+                "alice".to_string(),
+                "email".to_string(),
+                "alice@example.com".to_string(),
+            ),
+        )
+        .unwrap();
+        book.apply_update(
+            &our(),
+            Update::MergeContacts("alice".to_string(), "bob".to_string()),
+        )
+        .unwrap();
+        let socials = &book.contacts["alice"].socials;
+        assert_eq!(socials["email"], "alice@example.com");
+        assert_eq!(socials["email (bob)"], "bob@example.com");
+        assert!(!book.contacts.contains_key("bob"));
+        let trashed = &book.trash["bob"].contact;
+        assert_eq!(trashed.description.as_ref().unwrap().as_str(), "bob");
+    }
+
+    #[test]
+    fn merging_checks_the_combined_socials() {
+        let mut book = book_with_contact("");
+        let long_name = "b".repeat(MAX_NAME_LEN);
+        for (id, email) in [
+            ("alice", "alice@example.com"),
+            (long_name.as_str(), "b@example.com"),
+        ] {
+            book.contacts.entry(id.to_string()).or_default().socials =
+                BTreeMap::from([("email".to_string(), email.to_string())]);
+        }
+        let result = book.apply_update(
+            &our(),
+            Update::MergeContacts("alice".to_string(), long_name.clone()),
+        );
+        assert!(matches!(result, Err(UpdateError::TooLong { .. })));
+        assert!(book.contacts.contains_key(&long_name));
+        assert!(book.trash.is_empty());
+    }
+}
@@ -0,0 +1,225 @@
+//! What changed between two versions of a book, for reviewing what peers
+//! did while we were away. Versions are read with `history::book_at`.
+use crate::contact_book::{ContactBook, PeerStatus};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Default, Serialize)]
+pub struct BookDiff {
+    /// Name, description and the book's other settings.
+    pub settings: Vec<FieldChange>,
+    pub contacts: EntryChanges,
+    pub organizations: EntryChanges,
+    pub deals: EntryChanges,
+    pub tasks: EntryChanges,
+    pub relationships: EntryChanges,
+    pub peers_added: BTreeMap<String, PeerStatus>,
+    pub peers_removed: BTreeMap<String, PeerStatus>,
+    pub peers_changed: BTreeMap<String, (PeerStatus, PeerStatus)>,
+}
+
+/// Changes to one of a book's collections, such as its contacts.
+#[derive(Debug, Default, Serialize)]
+pub struct EntryChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// The fields that changed in each entry present in both versions.
+    pub changed: BTreeMap<String, Vec<FieldChange>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldChange {
+    /// Nested fields are joined with a dot, e.g. `socials.telegram`.
+    pub field: String,
+    /// `null` if the field wasn't set.
+    pub old: Value,
+    pub new: Value,
+}
+
+pub fn diff(before: &ContactBook, after: &ContactBook) -> anyhow::Result<BookDiff> {
+    let mut diff = BookDiff::default();
+    for (field, old, new) in [
+        ("name", json(&before.name)?, json(&after.name)?),
+        (
+            "description",
+            json(&before.description)?,
+            json(&after.description)?,
+        ),
+        ("color", json(&before.color)?, json(&after.color)?),
+        ("icon", json(&before.icon)?, json(&after.icon)?),
+        (
+            "default_peer_status",
+            json(&before.default_peer_status)?,
+            json(&after.default_peer_status)?,
+        ),
+        (
+            "deal_stages",
+            json(&before.deal_stages)?,
+            json(&after.deal_stages)?,
+        ),
+    ] {
+        if old != new {
+            diff.settings.push(FieldChange {
+                field: field.to_string(),
+                old,
+                new,
+            });
+        }
+    }
+    diff.contacts = entries(&before.contacts, &after.contacts)?;
+    diff.organizations = entries(&before.organizations, &after.organizations)?;
+    diff.deals = entries(&before.deals, &after.deals)?;
+    diff.tasks = entries(&before.tasks, &after.tasks)?;
+    diff.relationships = entries(&before.relationships, &after.relationships)?;
+
+    for (peer, status) in &after.peers {
+        match before.peers.get(peer) {
+            None => {
+                diff.peers_added.insert(peer.clone(), status.clone());
+            }
+            Some(old) if old != status => {
+                diff.peers_changed
+                    .insert(peer.clone(), (old.clone(), status.clone()));
+            }
+            Some(_) => {}
+        }
+    }
+    for (peer, status) in &before.peers {
+        if !after.peers.contains_key(peer) {
+            diff.peers_removed.insert(peer.clone(), status.clone());
+        }
+    }
+    Ok(diff)
+}
+
+fn json<T: Serialize>(value: &T) -> anyhow::Result<Value> {
+    Ok(serde_json::to_value(value)?)
+}
+
+fn entries<T: Serialize>(
+    before: &BTreeMap<String, T>,
+    after: &BTreeMap<String, T>,
+) -> anyhow::Result<EntryChanges> {
+    let mut changes = EntryChanges::default();
+    let keys: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    for key in keys {
+        match (before.get(key), after.get(key)) {
+            (None, Some(_)) => changes.added.push(key.clone()),
+            (Some(_), None) => changes.removed.push(key.clone()),
+            (Some(old), Some(new)) => {
+                let mut fields = vec![];
+                fields_changed("", json(old)?, json(new)?, &mut fields);
+                if !fields.is_empty() {
+                    changes.changed.insert(key.clone(), fields);
+                }
+            }
+            (None, None) => {}
+        }
+    }
+    Ok(changes)
+}
+
+/// Compare two values field by field, descending into objects so that
+/// e.g. a single changed social is reported on its own.
+fn fields_changed(prefix: &str, old: Value, new: Value, changes: &mut Vec<FieldChange>) {
+    if old == new {
+        return;
+    }
+    match (old, new) {
+        (Value::Object(mut old), Value::Object(mut new)) => {
+            let keys: BTreeSet<String> = old.keys().chain(new.keys()).cloned().collect();
+            for key in keys {
+                let field = match prefix {
+                    "" => key.clone(),
+                    prefix => format!("{}.{}", prefix, key),
+                };
+                let old = old.remove(&key).unwrap_or(Value::Null);
+                let new = new.remove(&key).unwrap_or(Value::Null);
+                fields_changed(&field, old, new, changes);
+            }
+        }
+        (old, new) => changes.push(FieldChange {
+            field: prefix.to_string(),
+            old,
+            new,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        contact_book::Contact,
+        testing::{address, our},
+    };
+    use autosurgeon::Text;
+    use serde_json::json;
+
+    fn book() -> ContactBook {
+        let mut book = ContactBook::new("Test".to_string(), &our());
+        for id in ["alice", "bob"] {
+            book.contacts.insert(
+                id.to_string(),
+                Contact {
+                    description: Some(Text::with_value("hello")),
+                    socials: BTreeMap::from([("email".to_string(), format!("{}@a.com", id))]),
+                    ..Default::default()
+                },
+            );
+        }
+        book
+    }
+
+    #[test]
+    fn unchanged_book_has_no_diff() {
+        let diff = diff(&book(), &book()).unwrap();
+        assert!(diff.settings.is_empty());
+        assert!(diff.contacts.added.is_empty());
+        assert!(diff.contacts.removed.is_empty());
+        assert!(diff.contacts.changed.is_empty());
+        assert!(diff.peers_added.is_empty());
+    }
+
+    #[test]
+    fn changes_are_reported_per_field() {
+        let before = book();
+        let mut after = book();
+        after.name = "Renamed".to_string();
+        after.contacts.remove("bob");
+        after
+            .contacts
+            .insert("carol".to_string(), Contact::default());
+        let alice = after.contacts.get_mut("alice").unwrap();
+        alice.description = Some(Text::with_value("hello there"));
+        alice
+            .socials
+            .insert("github".to_string(), "https://github.com/alice".to_string());
+        after
+            .peers
+            .insert(address("teammate.os").to_string(), PeerStatus::ReadWrite);
+
+        let diff = diff(&before, &after).unwrap();
+        assert_eq!(diff.settings.len(), 1);
+        assert_eq!(diff.settings[0].field, "name");
+        assert_eq!(diff.contacts.added, vec!["carol".to_string()]);
+        assert_eq!(diff.contacts.removed, vec!["bob".to_string()]);
+        let fields: Vec<(&str, &Value, &Value)> = diff.contacts.changed["alice"]
+            .iter()
+            .map(|change| (change.field.as_str(), &change.old, &change.new))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("description", &json!("hello"), &json!("hello there")),
+                (
+                    "socials.github",
+                    &Value::Null,
+                    &json!("https://github.com/alice")
+                ),
+            ]
+        );
+        assert_eq!(diff.peers_added.len(), 1);
+    }
+}
@@ -0,0 +1,166 @@
+//! Finding contacts in a book that are likely the same person, so they can
+//! be combined with `Update::MergeContacts`.
+use crate::contact_book::{Contact, ContactBook};
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// Report pairs scoring at least this much.
+const THRESHOLD: u32 = 50;
+const SAME_NAME: u32 = 60;
+/// Same words in the name, in a different order or with different punctuation.
+const SAME_NAME_WORDS: u32 = 40;
+/// Per social value (email, phone, handle...) the two contacts share.
+const SHARED_SOCIAL: u32 = 50;
+
+#[derive(Debug, Serialize)]
+pub struct Duplicate {
+    pub contacts: (String, String),
+    pub score: u32,
+    pub reasons: Vec<String>,
+}
+
+/// Every pair of contacts that look like duplicates, most likely first.
+pub fn find(book: &ContactBook) -> Vec<Duplicate> {
+    let contacts: Vec<(&String, &Contact)> = book.contacts.iter().collect();
+    let mut duplicates = vec![];
+    for (i, (a_id, a)) in contacts.iter().enumerate() {
+        for (b_id, b) in &contacts[i + 1..] {
+            let mut score = 0;
+            let mut reasons = vec![];
+            if normalize_name(a_id) == normalize_name(b_id) {
+                score += SAME_NAME;
+                reasons.push("same name".to_string());
+            } else if name_words(a_id) == name_words(b_id) {
+                score += SAME_NAME_WORDS;
+                reasons.push("same name words".to_string());
+            }
+            let b_values: BTreeSet<String> =
+                b.socials.values().map(|v| normalize_social(v)).collect();
+            for (key, value) in &a.socials {
+                let value = normalize_social(value);
+                if !value.is_empty() && b_values.contains(&value) {
+                    score += SHARED_SOCIAL;
+                    reasons.push(format!("shared {}", key));
+                }
+            }
+            if score >= THRESHOLD {
+                duplicates.push(Duplicate {
+                    contacts: ((*a_id).clone(), (*b_id).clone()),
+                    score,
+                    reasons,
+                });
+            }
+        }
+    }
+    duplicates.sort_by(|a, b| b.score.cmp(&a.score));
+    duplicates
+}
+
+fn normalize_name(name: &str) -> String {
+    words(name).collect::<Vec<_>>().join(" ")
+}
+
+fn name_words(name: &str) -> BTreeSet<String> {
+    words(name).collect()
+}
+
+fn words(name: &str) -> impl Iterator<Item = String> + '_ {
+    name.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+}
+
+/// Case, whitespace, URL schemes and phone number formatting shouldn't
+/// stop two values from matching.
+fn normalize_social(value: &str) -> String {
+    let value = value.trim().to_lowercase();
+    let value = value
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("www.")
+        .trim_start_matches("mailto:")
+        .trim_start_matches("tel:")
+        .trim_end_matches('/');
+    let is_phone = value
+        .chars()
+        .all(|c| c.is_ascii_digit() || " +-().".contains(c));
+    if is_phone {
+        value.chars().filter(|c| c.is_ascii_digit()).collect()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::our;
+    use std::collections::BTreeMap;
+
+    fn book(contacts: &[(&str, &[(&str, &str)])]) -> ContactBook {
+        let mut book = ContactBook::new("Test".to_string(), &our());
+        for (id, socials) in contacts {
+            let socials: BTreeMap<String, String> = socials
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            book.contacts.insert(
+                id.to_string(),
+                Contact {
+                    socials,
+                    ..Default::default()
+                },
+            );
+        }
+        book
+    }
+
+    #[test]
+    fn same_name_is_a_duplicate() {
+        let duplicates = find(&book(&[("Ada Lovelace", &[]), ("ada  lovelace", &[])]));
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].score, SAME_NAME);
+    }
+
+    #[test]
+    fn reordered_name_needs_more_evidence() {
+        let names: &[(&str, &[(&str, &str)])] = &[("Ada Lovelace", &[]), ("Lovelace, Ada", &[])];
+        assert!(find(&book(names)).is_empty());
+
+        let duplicates = find(&book(&[
+            ("Ada Lovelace", &[("phone", "+44 20 7946 0000")]),
+            ("Lovelace, Ada", &[("mobile", "tel:+442079460000")]),
+        ]));
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].score, SAME_NAME_WORDS + SHARED_SOCIAL);
+    }
+
+    #[test]
+    fn shared_social_is_a_duplicate() {
+        let duplicates = find(&book(&[
+            ("Ada", &[("email", "ada@example.com")]),
+            ("Countess", &[("email", "mailto:ADA@example.com ")]),
+            ("Charles", &[("email", "charles@example.com")]),
+        ]));
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(
+            duplicates[0].contacts,
+            ("Ada".to_string(), "Countess".to_string())
+        );
+        assert_eq!(duplicates[0].reasons, vec!["shared email".to_string()]);
+    }
+
+    #[test]
+    fn most_likely_come_first() {
+        let duplicates = find(&book(&[
+            ("Ada", &[("email", "ada@example.com")]),
+            ("ada", &[("email", "ada@example.com")]),
+            ("Countess", &[("email", "ada@example.com")]),
+        ]));
+        let scores: Vec<u32> = duplicates.iter().map(|d| d.score).collect();
+        assert_eq!(
+            scores,
+            vec![SAME_NAME + SHARED_SOCIAL, SHARED_SOCIAL, SHARED_SOCIAL]
+        );
+    }
+}
@@ -1,34 +1,112 @@
-use crate::{LocalContactsRequest, State};
+use crate::{
+    backups, blobs,
+    contact_book::{Attachment, ContactBook, Task, TRASH_RETENTION},
+    diff, duplicates, history,
+    request::Update,
+    response::UpdateError,
+    state::Reminder,
+    LocalContactsRequest, State,
+};
+use automerge::ChangeHash;
 use kinode_process_lib::{
     http,
     http::{HttpServerRequest, IncomingHttpRequest, Method, StatusCode},
     Address, Message,
 };
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use uuid::Uuid;
 
 const ICON: &str = include_str!("icon");
 
+/// Attachment types served as themselves, none of which a browser runs as
+/// script. Anything else a peer claims a file is gets served as plain bytes.
+const SAFE_ATTACHMENT_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "application/pdf",
+];
+
+/// Changes returned by `/books/:id/history` unless a `limit` is given.
+const HISTORY_PAGE_SIZE: usize = 50;
+const MAX_HISTORY_PAGE_SIZE: usize = 500;
+
 pub fn serve(our: &Address) {
     http::serve_ui(our, "ui", true, false, vec!["/"]).expect("couldn't serve UI");
     http::bind_http_path("/state", true, false).expect("couldn't bind HTTP state path");
     http::bind_http_path("/post", true, false).expect("couldn't bind HTTP post path");
+    http::bind_http_path("/organizations", true, false)
+        .expect("couldn't bind HTTP organizations path");
+    http::bind_http_path("/duplicates", true, false).expect("couldn't bind HTTP duplicates path");
+    http::bind_http_path("/trash", true, false).expect("couldn't bind HTTP trash path");
+    http::bind_http_path("/pipeline", true, false).expect("couldn't bind HTTP pipeline path");
+    http::bind_http_path("/attachment", true, false).expect("couldn't bind HTTP attachment path");
+    http::bind_http_path("/backups", true, false).expect("couldn't bind HTTP backups path");
+    http::bind_http_path("/neighborhood", true, false)
+        .expect("couldn't bind HTTP neighborhood path");
+    http::bind_http_path("/books/:id/history", true, false)
+        .expect("couldn't bind HTTP history path");
+    http::bind_http_path("/books/:id/at", true, false).expect("couldn't bind HTTP at path");
+    http::bind_http_path("/books/:id/diff", true, false).expect("couldn't bind HTTP diff path");
     http::bind_ws_path("/updates", true, false).expect("couldn't bind WS updates path");
 
     // add icon to homepage
     kinode_process_lib::homepage::add_to_homepage("Contacts", Some(ICON), Some("/"), None);
 }
 
+/// Everything the UI renders, as served at `/state` and pushed on `/updates`.
+fn state_json(state: &State) -> serde_json::Value {
+    let books = state.get_books_hydrated();
+    let now = crate::now();
+    let overdue_tasks: HashMap<&Uuid, _> = books
+        .iter()
+        .map(|(book_id, book)| (book_id, book.overdue_tasks(now)))
+        .collect();
+    // our private metadata is shown as a `local` field on each contact
+    let mut books_json = serde_json::json!(books);
+    for (book_id, contacts) in state.get_local_meta() {
+        for (contact_id, meta) in contacts {
+            if let Some(contact) = books_json
+                .get_mut(book_id.to_string())
+                .and_then(|book| book["contacts"].get_mut(contact_id))
+            {
+                contact["local"] = serde_json::json!(meta);
+            }
+        }
+    }
+    serde_json::json!({
+        "books": books_json,
+        "pending_invites": state.get_invites(),
+        "pending_cards": state.get_cards(),
+        "overdue_tasks": overdue_tasks,
+        "conflicts": state.get_conflicts(),
+        "load_error": state.get_load_error(),
+    })
+}
+
 pub fn send_ws_updates(state: &State, ws_channels: &HashSet<u32>) {
     if ws_channels.is_empty() {
         return;
     }
-    let bytes = serde_json::json!({
-        "books": state.get_books_hydrated(),
-        "pending_invites": state.get_invites(),
-    })
-    .to_string()
-    .as_bytes()
-    .to_vec();
+    send_ws_json(state_json(state), ws_channels);
+}
+
+pub fn send_ws_reminder(reminder: &Reminder, task: &Task, ws_channels: &HashSet<u32>) {
+    send_ws_json(
+        serde_json::json!({
+            "reminder": {
+                "book_id": reminder.book_id,
+                "task_id": reminder.task_id,
+                "task": task,
+            }
+        }),
+        ws_channels,
+    );
+}
+
+fn send_ws_json(json: serde_json::Value, ws_channels: &HashSet<u32>) {
+    let bytes = json.to_string().as_bytes().to_vec();
     for channel_id in ws_channels.iter() {
         http::send_ws_push(
             *channel_id,
@@ -55,20 +133,40 @@ pub fn handle_http_request(
     };
 
     match req {
-        HttpServerRequest::Http(req) => match serve_http_paths(our, req, state, ws_channels) {
-            Ok((status_code, body)) => http::send_response(
-                status_code,
-                Some(std::collections::HashMap::from([(
-                    String::from("Content-Type"),
-                    String::from("application/json"),
-                )])),
-                body,
-            ),
-            Err(e) => {
-                http::send_response(StatusCode::INTERNAL_SERVER_ERROR, None, vec![]);
-                return Err(e);
+        HttpServerRequest::Http(req) => {
+            // attachments are served with their own content type, everything else is JSON
+            let is_attachment = req.path()? == "/attachment";
+            let result = if is_attachment {
+                serve_attachment(our, req, state, ws_channels)
+            } else {
+                serve_http_paths(our, req, state, ws_channels)
+                    .map(|(status_code, body)| (status_code, "application/json".to_string(), body))
+            };
+            match result {
+                Ok((status_code, content_type, body)) => {
+                    let mut headers = HashMap::from([
+                        (String::from("Content-Type"), content_type),
+                        // never let the browser guess a type other than the one we give
+                        (
+                            String::from("X-Content-Type-Options"),
+                            String::from("nosniff"),
+                        ),
+                    ]);
+                    if is_attachment {
+                        // files come from peers: download them, never open them on our origin
+                        headers.insert(
+                            String::from("Content-Disposition"),
+                            String::from("attachment"),
+                        );
+                    }
+                    http::send_response(status_code, Some(headers), body)
+                }
+                Err(e) => {
+                    http::send_response(StatusCode::INTERNAL_SERVER_ERROR, None, vec![]);
+                    return Err(e);
+                }
             }
-        },
+        }
         HttpServerRequest::WebSocketOpen { channel_id, .. } => {
             // save channel id for pushing
             ws_channels.insert(channel_id);
@@ -93,7 +191,7 @@ pub fn serve_http_paths(
     let method = req.method()?;
     // strips first section of path, which is the process name
     let bound_path = req.path()?;
-    let _url_params = req.url_params();
+    let url_params = req.url_params();
 
     match bound_path.as_str() {
         "/state" => {
@@ -102,15 +200,198 @@ pub fn serve_http_paths(
             }
             Ok((
                 StatusCode::OK,
-                serde_json::json!({
-                    "books": state.get_books_hydrated(),
-                    "pending_invites": state.get_invites(),
+                state_json(state).to_string().as_bytes().to_vec(),
+            ))
+        }
+        "/organizations" => {
+            if method != Method::GET {
+                return Ok((StatusCode::METHOD_NOT_ALLOWED, vec![]));
+            }
+            // for each book, each organization alongside the contacts linked to it
+            let organizations: HashMap<Uuid, BTreeMap<String, serde_json::Value>> = state
+                .get_books_hydrated()
+                .into_iter()
+                .map(|(book_id, book)| {
+                    let members = book.contacts_by_organization();
+                    let orgs = book
+                        .organizations
+                        .into_iter()
+                        .map(|(org_id, organization)| {
+                            let value = serde_json::json!({
+                                "organization": organization,
+                                "contacts": members.get(&org_id),
+                            });
+                            (org_id, value)
+                        })
+                        .collect();
+                    (book_id, orgs)
+                })
+                .collect();
+            Ok((StatusCode::OK, serde_json::to_vec(&organizations)?))
+        }
+        "/duplicates" => {
+            if method != Method::GET {
+                return Ok((StatusCode::METHOD_NOT_ALLOWED, vec![]));
+            }
+            // for each book, pairs of contacts that are likely the same person
+            let duplicates: HashMap<Uuid, _> = state
+                .get_books_hydrated()
+                .iter()
+                .map(|(book_id, book)| (*book_id, duplicates::find(book)))
+                .collect();
+            Ok((StatusCode::OK, serde_json::to_vec(&duplicates)?))
+        }
+        "/trash" => {
+            if method != Method::GET {
+                return Ok((StatusCode::METHOD_NOT_ALLOWED, vec![]));
+            }
+            // for each book, removed contacts and when they will be purged
+            let trash: HashMap<Uuid, BTreeMap<String, serde_json::Value>> = state
+                .get_books_hydrated()
+                .into_iter()
+                .map(|(book_id, book)| {
+                    let trash = book
+                        .trash
+                        .into_iter()
+                        .map(|(id, trashed)| {
+                            let value = serde_json::json!({
+                                "purge_at": trashed.deleted_at + TRASH_RETENTION,
+                                "trashed": trashed,
+                            });
+                            (id, value)
+                        })
+                        .collect();
+                    (book_id, trash)
                 })
-                .to_string()
-                .as_bytes()
-                .to_vec(),
+                .collect();
+            Ok((StatusCode::OK, serde_json::to_vec(&trash)?))
+        }
+        "/pipeline" => {
+            if method != Method::GET {
+                return Ok((StatusCode::METHOD_NOT_ALLOWED, vec![]));
+            }
+            let pipelines: HashMap<Uuid, _> = state
+                .get_books_hydrated()
+                .into_iter()
+                .map(|(book_id, book)| (book_id, book.pipeline()))
+                .collect();
+            Ok((StatusCode::OK, serde_json::to_vec(&pipelines)?))
+        }
+        "/backups" => {
+            if method != Method::GET {
+                return Ok((StatusCode::METHOD_NOT_ALLOWED, vec![]));
+            }
+            // oldest first, restored with `RestoreBackup`
+            Ok((StatusCode::OK, serde_json::to_vec(&backups::list(our)?)?))
+        }
+        "/neighborhood" => {
+            // ?book=<book_id>&contact=<contact_id>
+            if method != Method::GET {
+                return Ok((StatusCode::METHOD_NOT_ALLOWED, vec![]));
+            }
+            let query_params = req.query_params();
+            let (Some(book_id), Some(contact_id)) =
+                (query_params.get("book"), query_params.get("contact"))
+            else {
+                return Ok((StatusCode::BAD_REQUEST, vec![]));
+            };
+            let Ok(book_id) = book_id.parse::<Uuid>() else {
+                return Ok((StatusCode::BAD_REQUEST, vec![]));
+            };
+            let Some(book) = state.get_books_hydrated().remove(&book_id) else {
+                return Ok((StatusCode::NOT_FOUND, vec![]));
+            };
+            if !book.contacts.contains_key(contact_id) {
+                return Ok((StatusCode::NOT_FOUND, vec![]));
+            }
+            Ok((
+                StatusCode::OK,
+                serde_json::to_vec(&book.neighborhood(contact_id))?,
             ))
         }
+        "/books/:id/history" => {
+            if method != Method::GET {
+                return Ok((StatusCode::METHOD_NOT_ALLOWED, vec![]));
+            }
+            let Some(Ok(book_id)) = url_params.get("id").map(|id| id.parse::<Uuid>()) else {
+                return Ok((StatusCode::BAD_REQUEST, vec![]));
+            };
+            let Some(crdt) = state.get_book_mut(&book_id) else {
+                return Ok((StatusCode::NOT_FOUND, vec![]));
+            };
+            // `?offset=<n>&limit=<n>`, newest first
+            let query_params = req.query_params();
+            let offset = query_params
+                .get("offset")
+                .and_then(|o| o.parse().ok())
+                .unwrap_or(0);
+            let limit = query_params
+                .get("limit")
+                .and_then(|l| l.parse().ok())
+                .unwrap_or(HISTORY_PAGE_SIZE)
+                .min(MAX_HISTORY_PAGE_SIZE);
+            let page = history::page(crdt, offset, limit);
+            Ok((StatusCode::OK, serde_json::to_vec(&page)?))
+        }
+        "/books/:id/at" => {
+            // ?heads=<hash>,<hash>... as listed in the book's history
+            if method != Method::GET {
+                return Ok((StatusCode::METHOD_NOT_ALLOWED, vec![]));
+            }
+            let Some(Ok(book_id)) = url_params.get("id").map(|id| id.parse::<Uuid>()) else {
+                return Ok((StatusCode::BAD_REQUEST, vec![]));
+            };
+            let Some(Some(heads)) = req.query_params().get("heads").map(|h| parse_heads(h)) else {
+                return Ok((StatusCode::BAD_REQUEST, vec![]));
+            };
+            let Some(crdt) = state.get_book_mut(&book_id) else {
+                return Ok((StatusCode::NOT_FOUND, vec![]));
+            };
+            match history::book_at(crdt, &heads) {
+                Ok(book) => Ok((StatusCode::OK, serde_json::to_vec(&book)?)),
+                // heads we've never seen
+                Err(e) if e.downcast_ref::<UpdateError>().is_some() => {
+                    Ok((StatusCode::NOT_FOUND, vec![]))
+                }
+                Err(e) => Err(e),
+            }
+        }
+        "/books/:id/diff" => {
+            // ?from=<heads>&to=<heads>, each a comma-separated list of hashes.
+            // `from` defaults to our last visit, `to` to the book as it is now.
+            if method != Method::GET {
+                return Ok((StatusCode::METHOD_NOT_ALLOWED, vec![]));
+            }
+            let Some(Ok(book_id)) = url_params.get("id").map(|id| id.parse::<Uuid>()) else {
+                return Ok((StatusCode::BAD_REQUEST, vec![]));
+            };
+            let query_params = req.query_params();
+            let from = match query_params.get("from") {
+                Some(from) => parse_heads(from),
+                None => state.get_last_visit(&book_id).cloned(),
+            };
+            let to = match query_params.get("to") {
+                Some(to) => parse_heads(to),
+                None => state.get_heads(&book_id),
+            };
+            let (Some(from), Some(to)) = (from, to) else {
+                return Ok((StatusCode::BAD_REQUEST, vec![]));
+            };
+            let Some(crdt) = state.get_book_mut(&book_id) else {
+                return Ok((StatusCode::NOT_FOUND, vec![]));
+            };
+            let (before, after) = match (history::book_at(crdt, &from), history::book_at(crdt, &to))
+            {
+                (Ok(before), Ok(after)) => (before, after),
+                // heads we've never seen
+                (Err(e), _) | (_, Err(e)) if e.downcast_ref::<UpdateError>().is_some() => {
+                    return Ok((StatusCode::NOT_FOUND, vec![]));
+                }
+                (Err(e), _) | (_, Err(e)) => return Err(e),
+            };
+            let diff = diff::diff(&before, &after)?;
+            Ok((StatusCode::OK, serde_json::to_vec(&diff)?))
+        }
         "/post" => {
             if method != Method::POST {
                 return Ok((StatusCode::METHOD_NOT_ALLOWED, vec![]));
@@ -118,12 +399,132 @@ pub fn serve_http_paths(
             let json_bytes = kinode_process_lib::get_blob()
                 .ok_or(anyhow::anyhow!("http POST without body"))?
                 .bytes;
-            let request: LocalContactsRequest = serde_json::from_slice(&json_bytes)?;
-            crate::handle_local_request(&our, request, state)?;
+            let request: LocalContactsRequest = match serde_json::from_slice(&json_bytes) {
+                Ok(request) => request,
+                Err(e) => {
+                    let error = UpdateError::invalid("request", e);
+                    return Ok((StatusCode::BAD_REQUEST, serde_json::to_vec(&error)?));
+                }
+            };
+            if let Err(e) = crate::handle_local_request(&our, request, state) {
+                // rejected requests are the caller's fault: tell them why
+                let Some(error) = e.downcast_ref::<UpdateError>() else {
+                    return Err(e);
+                };
+                return Ok((error_status(error), serde_json::to_vec(error)?));
+            }
             send_ws_updates(state, ws_channels);
-            state.persist();
+            state.schedule_reminders(our);
+            state.persist(our);
             Ok((StatusCode::OK, vec![]))
         }
         _ => Ok((StatusCode::NOT_FOUND, vec![])),
     }
 }
+
+/// A comma-separated list of change hashes, as listed in a book's history.
+fn parse_heads(heads: &str) -> Option<Vec<ChangeHash>> {
+    heads.split(',').map(|hash| hash.parse().ok()).collect()
+}
+
+fn error_status(error: &UpdateError) -> StatusCode {
+    match error {
+        UpdateError::NotFound { .. } => StatusCode::NOT_FOUND,
+        UpdateError::Duplicate { .. } => StatusCode::CONFLICT,
+        UpdateError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+        UpdateError::EmptyId { .. }
+        | UpdateError::TooLong { .. }
+        | UpdateError::Invalid { .. }
+        | UpdateError::Batch(_) => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// `GET /attachment?book=<book_id>&hash=<hash>` serves a stored avatar or attachment.
+/// If we don't have it yet, we ask the book's peers for it and return 404 for now.
+///
+/// `POST /attachment?book=<book_id>&contact=<contact_id>&name=<file name>&mime=<type>`
+/// stores the body and attaches it to the contact. With `&avatar=true`, it
+/// becomes the contact's avatar instead.
+fn serve_attachment(
+    our: &Address,
+    req: IncomingHttpRequest,
+    state: &mut State,
+    ws_channels: &mut HashSet<u32>,
+) -> anyhow::Result<(StatusCode, String, Vec<u8>)> {
+    let method = req.method()?;
+    let query_params = req.query_params();
+    let json = "application/json".to_string();
+    let Some(Ok(book_id)) = query_params.get("book").map(|id| id.parse::<Uuid>()) else {
+        return Ok((StatusCode::BAD_REQUEST, json, vec![]));
+    };
+
+    if method == Method::GET {
+        let Some(hash) = query_params.get("hash") else {
+            return Ok((StatusCode::BAD_REQUEST, json, vec![]));
+        };
+        let Some(book) = state.get_books_hydrated().remove(&book_id) else {
+            return Ok((StatusCode::NOT_FOUND, json, vec![]));
+        };
+        if !book.references_blob(hash) {
+            return Ok((StatusCode::NOT_FOUND, json, vec![]));
+        }
+        let Some(bytes) = blobs::load(our, hash) else {
+            crate::request_blob(our, book_id, &book, hash, state)?;
+            return Ok((StatusCode::NOT_FOUND, json, vec![]));
+        };
+        let mime = book
+            .contacts
+            .values()
+            .find_map(|c| c.attachments.get(hash))
+            .and_then(|a| a.mime.clone())
+            .filter(|mime| SAFE_ATTACHMENT_TYPES.contains(&mime.as_str()))
+            .unwrap_or("application/octet-stream".to_string());
+        Ok((StatusCode::OK, mime, bytes))
+    } else if method == Method::POST {
+        let Some(contact_id) = query_params.get("contact") else {
+            return Ok((StatusCode::BAD_REQUEST, json, vec![]));
+        };
+        // check the contact first, so that a rejected upload leaves no orphan blob
+        let Some(crdt) = state.get_book_mut(&book_id) else {
+            let error = UpdateError::not_found("book", &book_id.to_string());
+            return Ok((error_status(&error), json, serde_json::to_vec(&error)?));
+        };
+        let book: ContactBook = autosurgeon::hydrate(crdt)?;
+        if !book.contacts.contains_key(contact_id) {
+            let error = UpdateError::not_found("contact", contact_id);
+            return Ok((error_status(&error), json, serde_json::to_vec(&error)?));
+        }
+        let bytes = kinode_process_lib::get_blob()
+            .ok_or(anyhow::anyhow!("http POST without body"))?
+            .bytes;
+        let hash = blobs::save(our, &bytes)?;
+        let update = if query_params.get("avatar").map(|a| a.as_str()) == Some("true") {
+            Update::SetContactAvatar(contact_id.clone(), Some(hash.clone()))
+        } else {
+            Update::AddContactAttachment(
+                contact_id.clone(),
+                hash.clone(),
+                Attachment {
+                    name: query_params.get("name").unwrap_or(&hash).clone(),
+                    mime: query_params.get("mime").cloned(),
+                    size: bytes.len() as u64,
+                },
+            )
+        };
+        if let Err(e) = crate::handle_update(our, book_id, update, state) {
+            let Some(error) = e.downcast_ref::<UpdateError>() else {
+                return Err(e);
+            };
+            return Ok((error_status(error), json, serde_json::to_vec(error)?));
+        }
+        send_ws_updates(state, ws_channels);
+        state.persist(our);
+        Ok((
+            StatusCode::OK,
+            json,
+            serde_json::json!({ "hash": hash }).to_string().into_bytes(),
+        ))
+    } else {
+        Ok((StatusCode::METHOD_NOT_ALLOWED, json, vec![]))
+    }
+}
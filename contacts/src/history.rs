@@ -0,0 +1,351 @@
+//! A book's audit log and past versions, read from its automerge change
+//! history. Every change made through `handle_update` is committed with a
+//! `ChangeNote` as its message, saying who made it and what it did.
+use crate::{
+    conflicts::ConflictField,
+    contact_book::{ContactBook, RelationshipKind},
+    migrations,
+    request::Update,
+    response::UpdateError,
+    socials,
+};
+use automerge::{transaction::CommitOptions, AutoCommit, ChangeHash};
+use kinode_process_lib::Address;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Stored as JSON in the commit message of each change.
+#[derive(Debug, Serialize, Deserialize)]
+struct ChangeNote {
+    /// Node of the peer that made the change.
+    author: String,
+    summary: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryEntry {
+    pub hash: String,
+    /// `None` if nothing tells us who made the change.
+    pub author: Option<String>,
+    /// Seconds since the unix epoch, as claimed by the author.
+    pub timestamp: i64,
+    /// `None` for changes not made through an update, such as migrations,
+    /// or made by peers running a version of the app without history.
+    pub summary: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryPage {
+    /// Number of changes in the book's history.
+    pub total: usize,
+    /// Newest first.
+    pub changes: Vec<HistoryEntry>,
+}
+
+/// Commit everything pending in `doc` as one change, noting that `author`
+/// made it and what it did.
+pub fn commit(doc: &mut AutoCommit, author: &Address, summary: String) -> anyhow::Result<()> {
+    let note = serde_json::to_string(&ChangeNote {
+        author: author.node.clone(),
+        summary,
+    })?;
+    doc.commit_with(
+        CommitOptions::default()
+            .with_message(note)
+            .with_time(crate::now() as i64),
+    );
+    Ok(())
+}
+
+/// Up to `limit` changes, newest first, skipping the `offset` newest.
+pub fn page(doc: &mut AutoCommit, offset: usize, limit: usize) -> HistoryPage {
+    let changes = doc.get_changes(&[]);
+    let notes: Vec<Option<ChangeNote>> = changes
+        .iter()
+        .map(|change| {
+            change
+                .message()
+                .and_then(|message| serde_json::from_str(message).ok())
+        })
+        .collect();
+    // an actor is only ever used by one node, so changes without a note
+    // can be attributed to whoever made the other changes of their actor
+    let authors: HashMap<_, _> = changes
+        .iter()
+        .zip(&notes)
+        .filter_map(|(change, note)| Some((change.actor_id(), note.as_ref()?.author.clone())))
+        .collect();
+    let total = changes.len();
+    let changes = changes
+        .iter()
+        .zip(notes)
+        .rev()
+        .skip(offset)
+        .take(limit)
+        .map(|(change, note)| HistoryEntry {
+            hash: change.hash().to_string(),
+            author: note
+                .as_ref()
+                .map(|note| note.author.clone())
+                .or_else(|| authors.get(change.actor_id()).cloned()),
+            timestamp: change.timestamp(),
+            summary: note.map(|note| note.summary),
+        })
+        .collect();
+    HistoryPage { total, changes }
+}
+
+/// The book as it was at `heads`, brought up to the current schema.
+pub fn book_at(doc: &mut AutoCommit, heads: &[ChangeHash]) -> anyhow::Result<ContactBook> {
+    let mut past = doc
+        .fork_at(heads)
+        .map_err(|e| UpdateError::invalid("heads", e))?;
+    migrations::migrate(&mut past)?;
+    Ok(autosurgeon::hydrate(&past)?)
+}
+
+/// Revert what changed between `before` and `after`, two versions of the
+/// book, as a new change by `author`. The change is made on top of `after`
+/// and then merged in, so it undoes only what changed in between, down to a
+/// single social or span of a description, and keeps every edit made since.
+pub fn revert(
+    doc: &mut AutoCommit,
+    author: &Address,
+    before: &[ChangeHash],
+    after: &[ChangeHash],
+) -> anyhow::Result<()> {
+    let old = book_at(doc, before)?;
+    let mut fork = doc
+        .fork_at(after)
+        .map_err(|e| UpdateError::invalid("heads", e))?;
+    let mut book: ContactBook = autosurgeon::hydrate(&fork)?;
+    book.revert_to(author, old);
+    autosurgeon::reconcile(&mut fork, &book)?;
+    commit(
+        &mut fork,
+        author,
+        "undid or redid an earlier change".to_string(),
+    )?;
+    doc.merge(&mut fork)?;
+    Ok(())
+}
+
+/// Replace every `RestoreContactVersion` in `update` with a `PutContact`
+/// of the contact as it was at the given heads.
+pub fn resolve_versions(doc: &mut AutoCommit, update: Update) -> anyhow::Result<Update> {
+    Ok(match update {
+        Update::RestoreContactVersion(id, heads) => {
+            let mut past = book_at(doc, &heads)?;
+            let contact = match past.contacts.remove(&id) {
+                Some(contact) => contact,
+                None => {
+                    past.trash
+                        .remove(&id)
+                        .ok_or(UpdateError::not_found("contact", &id))?
+                        .contact
+                }
+            };
+            Update::PutContact(id, contact)
+        }
+        Update::Batch(updates) => Update::Batch(
+            updates
+                .into_iter()
+                .map(|update| resolve_versions(doc, update))
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        update => update,
+    })
+}
+
+/// What `update` does, for someone who knows `book` as it is before the
+/// update, e.g. "edited Bob's telegram". Read as following the author's name.
+pub fn describe(book: &ContactBook, author: &Address, update: &Update) -> String {
+    match update {
+        Update::AddContact(id, _) => format!("added contact {}", id),
+        Update::RemoveContact(id) => format!("moved {} to the trash", id),
+        Update::RestoreContact(id) => format!("restored {} from the trash", id),
+        Update::DeleteFromTrash(id) => format!("permanently deleted {}", id),
+        Update::RestoreContactVersion(id, _) | Update::PutContact(id, _) => {
+            format!("restored an earlier version of {}", id)
+        }
+        Update::MergeContacts(keep, merge) => format!("merged {} into {}", merge, keep),
+        Update::EditContactDescription(id, _) | Update::SpliceContactDescription(id, ..) => {
+            format!("edited {}'s description", id)
+        }
+        Update::ResolveConflict(id, ConflictField::Description, _) => {
+            format!("resolved a conflict in {}'s description", id)
+        }
+        Update::ResolveConflict(id, ConflictField::Social(key), _) => {
+            format!("resolved a conflict in {}'s {}", id, key)
+        }
+        Update::EditContactSocial(id, key, _) => {
+            let key = socials::normalize_key(key);
+            match book.contacts.get(id).map(|c| c.socials.contains_key(&key)) {
+                Some(true) => format!("edited {}'s {}", id, key),
+                _ => format!("added {}'s {}", id, key),
+            }
+        }
+        Update::RemoveContactSocial(id, key) => format!("removed {}'s {}", id, key),
+        Update::SetContactNode(id, Some(node)) => format!("set {}'s node to {}", id, node),
+        Update::SetContactNode(id, None) => format!("cleared {}'s node", id),
+        Update::SetContactAvatar(id, Some(_)) => format!("changed {}'s avatar", id),
+        Update::SetContactAvatar(id, None) => format!("removed {}'s avatar", id),
+        Update::AddContactAttachment(id, _, attachment) => {
+            format!("attached {} to {}", attachment.name, id)
+        }
+        Update::RemoveContactAttachment(id, hash) => {
+            let name = book
+                .contacts
+                .get(id)
+                .and_then(|c| c.attachments.get(hash))
+                .map(|a| a.name.as_str())
+                .unwrap_or("an attachment");
+            format!("removed {} from {}", name, id)
+        }
+        Update::AddOrganization(_, organization) => {
+            format!("added organization {}", organization.name)
+        }
+        Update::EditOrganization(_, organization) => {
+            format!("edited organization {}", organization.name)
+        }
+        Update::RemoveOrganization(id) => {
+            format!("removed organization {}", organization_name(book, id))
+        }
+        Update::LinkContactToOrganization(id, org_id) => {
+            format!("added {} to {}", id, organization_name(book, org_id))
+        }
+        Update::UnlinkContactFromOrganization(id) => {
+            match book.contacts.get(id).and_then(|c| c.organization.as_ref()) {
+                Some(org_id) => format!("removed {} from {}", id, organization_name(book, org_id)),
+                None => format!("removed {} from their organization", id),
+            }
+        }
+        Update::SetDealStages(_) => "changed the deal stages".to_string(),
+        Update::AddDeal(_, deal) => format!("added deal {}", deal.title),
+        Update::MoveDeal(id, stage) => format!("moved deal {} to {}", deal_title(book, id), stage),
+        Update::RemoveDeal(id) => format!("removed deal {}", deal_title(book, id)),
+        Update::AddTask(_, task) => format!("added task {} for {}", task.title, task.contact),
+        Update::SetTaskDone(id, true) => format!("completed task {}", task_title(book, id)),
+        Update::SetTaskDone(id, false) => format!("reopened task {}", task_title(book, id)),
+        Update::RemoveTask(id) => format!("removed task {}", task_title(book, id)),
+        Update::AddRelationship(r) => format!(
+            "noted that {} {} {}",
+            r.from,
+            relationship_kind(&r.kind),
+            r.to
+        ),
+        Update::RemoveRelationship(r) => format!(
+            "removed that {} {} {}",
+            r.from,
+            relationship_kind(&r.kind),
+            r.to
+        ),
+        Update::SetBookSettings(_) => "changed the book settings".to_string(),
+        Update::PurgeTrash => "emptied expired contacts from the trash".to_string(),
+        Update::AddPeer(address, _) => format!("added peer {}", address.node),
+        Update::RemovePeer(address) if address == author => "left the book".to_string(),
+        Update::RemovePeer(address) => format!("removed peer {}", address.node),
+        Update::Batch(updates) => updates
+            .iter()
+            .map(|update| describe(book, author, update))
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+}
+
+fn organization_name<'a>(book: &'a ContactBook, id: &'a str) -> &'a str {
+    book.organizations
+        .get(id)
+        .map(|o| o.name.as_str())
+        .unwrap_or(id)
+}
+
+fn deal_title<'a>(book: &'a ContactBook, id: &'a str) -> &'a str {
+    book.deals.get(id).map(|d| d.title.as_str()).unwrap_or(id)
+}
+
+fn task_title<'a>(book: &'a ContactBook, id: &'a str) -> &'a str {
+    book.tasks.get(id).map(|t| t.title.as_str()).unwrap_or(id)
+}
+
+fn relationship_kind(kind: &RelationshipKind) -> &str {
+    match kind {
+        RelationshipKind::IntroducedBy => "was introduced by",
+        RelationshipKind::ReportsTo => "reports to",
+        RelationshipKind::SpouseOf => "is the spouse of",
+        RelationshipKind::Other(kind) => kind,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        contact_book::{Contact, PeerStatus},
+        testing::{address, our},
+    };
+    use autosurgeon::{hydrate, reconcile, Text};
+
+    fn teammate() -> Address {
+        address("teammate.os")
+    }
+
+    fn edit(doc: &mut AutoCommit, author: &Address, updates: Vec<Update>) {
+        let mut book: ContactBook = hydrate(doc).unwrap();
+        for update in updates {
+            book.apply_update(author, update).unwrap();
+        }
+        reconcile(doc, &book).unwrap();
+        commit(doc, author, "edited".to_string()).unwrap();
+    }
+
+    #[test]
+    fn revert_keeps_later_edits() {
+        let alice = || "alice".to_string();
+        let mut book = ContactBook::new("Test".to_string(), &our());
+        book.peers
+            .insert(teammate().to_string(), PeerStatus::ReadWrite);
+        book.contacts.insert(
+            alice(),
+            Contact {
+                description: Some(Text::with_value("hello world")),
+                ..Default::default()
+            },
+        );
+        let mut doc = AutoCommit::new();
+        reconcile(&mut doc, &book).unwrap();
+        commit(&mut doc, &our(), "created".to_string()).unwrap();
+
+        let before = doc.get_heads();
+        edit(
+            &mut doc,
+            &our(),
+            vec![
+                Update::SpliceContactDescription(alice(), 5, 0, " brave".to_string()),
+                Update::EditContactSocial(alice(), "github".to_string(), "alice".to_string()),
+            ],
+        );
+        let after = doc.get_heads();
+
+        let mut theirs = doc.fork();
+        edit(
+            &mut theirs,
+            &teammate(),
+            vec![
+                Update::SpliceContactDescription(alice(), 17, 0, "!".to_string()),
+                Update::EditContactSocial(alice(), "email".to_string(), "a@b.com".to_string()),
+            ],
+        );
+        doc.merge(&mut theirs).unwrap();
+
+        revert(&mut doc, &our(), &before, &after).unwrap();
+        let book: ContactBook = hydrate(&doc).unwrap();
+        let contact = &book.contacts["alice"];
+        assert_eq!(
+            contact.description.as_ref().unwrap().as_str(),
+            "hello world!"
+        );
+        assert!(!contact.socials.contains_key("github"));
+        assert_eq!(contact.socials["email"], "a@b.com");
+    }
+}
@@ -1,21 +1,33 @@
 #![feature(let_chains)]
 use crate::{
     contact_book::{Contact, ContactBook, PeerStatus},
-    request::{LocalContactsRequest, RemoteContactsRequest, Update},
-    response::{ContactsError, ContactsResponse},
-    state::{Invite, State},
+    migrations::SCHEMA_VERSION,
+    request::{LocalContactsRequest, LocalUpdate, RemoteContactsRequest, Update},
+    response::{ContactsError, ContactsResponse, UpdateError},
+    state::{ContactCard, Edit, Invite, Reminder, State},
 };
 use automerge::AutoCommit;
 use autosurgeon::{hydrate, reconcile};
 use kinode_process_lib::{await_message, println, Address, Message, Request, Response};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use uuid::Uuid;
 
+mod backups;
+mod blobs;
+mod conflicts;
 mod contact_book;
+mod diff;
+mod duplicates;
 mod frontend;
+mod history;
+mod migrations;
 mod request;
 mod response;
+mod socials;
 mod state;
+mod storage;
+#[cfg(test)]
+mod testing;
 
 wit_bindgen::generate!({
     path: "wit",
@@ -24,23 +36,34 @@ wit_bindgen::generate!({
 
 const TIMEOUT: u64 = 30;
 
+/// Any node can send us contact cards, so cap how many we hold from each
+/// sender and how large each may be, in bytes.
+const MAX_CARDS_PER_SENDER: usize = 20;
+const MAX_CARD_SIZE: usize = 64 * 1024;
+
+/// Current time in seconds since the unix epoch.
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
 kinode_process_lib::call_init!(init);
 fn init(our: Address) {
-    let mut state = if let Some(state) = kinode_process_lib::get_state()
-        && let Ok(state) = serde_json::from_slice::<State>(&state)
-    {
-        println!("loading saved state");
-        state
-    } else {
-        println!("generating new state");
-        let state = State::new(&our);
-        state
-    };
+    let mut state = State::load(&our);
 
     let mut ws_channels: HashSet<u32> = HashSet::new();
     frontend::serve(&our);
+    blobs::create_drive(&our).expect("couldn't create blob drive");
+    backups::create_drive(&our).expect("couldn't create backup drive");
 
     kinode_process_lib::timer::set_timer(30_000, None);
+    kinode_process_lib::timer::set_timer(
+        backups::next_due(&our) * 1000,
+        Some(backups::TIMER_CONTEXT.to_vec()),
+    );
+    state.schedule_reminders(&our);
 
     loop {
         handle_message(&our, &mut state, &mut ws_channels)
@@ -58,6 +81,29 @@ fn handle_message(
         Ok(message) => {
             if message.is_local(our) {
                 if message.is_process("timer:distro:sys") {
+                    if message.context() == Some(backups::TIMER_CONTEXT) {
+                        // daily upkeep: empty expired trash, then back up
+                        kinode_process_lib::timer::set_timer(
+                            backups::INTERVAL * 1000,
+                            Some(backups::TIMER_CONTEXT.to_vec()),
+                        );
+                        purge_expired_trash(our, state);
+                        frontend::send_ws_updates(&state, ws_channels);
+                        state.persist(our);
+                        let name = backups::save(our, &state.archive()?)?;
+                        println!("saved backup {}", name);
+                        return Ok(());
+                    }
+                    if let Some(context) = message.context() {
+                        // a task's due date has passed: remind the user
+                        let reminder: Reminder = serde_json::from_slice(context)?;
+                        if let Some(task) = state.take_due_reminder(&reminder) {
+                            frontend::send_ws_reminder(&reminder, &task, ws_channels);
+                        }
+                        // due date may have moved later, in which case we need a new timer
+                        state.schedule_reminders(our);
+                        return Ok(());
+                    }
                     // every 30 seconds, try re-sending failed messages
                     // should really do some exponential backoff here
                     state.retry_all_failed_messages()?;
@@ -71,7 +117,8 @@ fn handle_message(
                     if message.is_request() {
                         handle_local_request(our, serde_json::from_slice(message.body())?, state)?;
                         frontend::send_ws_updates(&state, ws_channels);
-                        state.persist();
+                        state.schedule_reminders(our);
+                        state.persist(our);
                         Ok(())
                     } else {
                         Ok(())
@@ -82,7 +129,8 @@ fn handle_message(
                 if message.is_request() {
                     handle_remote_message(our, message, state)?;
                     frontend::send_ws_updates(&state, ws_channels);
-                    state.persist();
+                    state.schedule_reminders(our);
+                    state.persist(our);
                     Ok(())
                 } else {
                     Ok(())
@@ -113,9 +161,60 @@ fn handle_local_request(
 ) -> anyhow::Result<()> {
     match request {
         LocalContactsRequest::Update(book_id, update) => {
+            let Some(before) = state.get_heads(&book_id) else {
+                return Err(UpdateError::not_found("book", &book_id.to_string()).into());
+            };
             handle_update(our, book_id, update, state)?;
+            if let Some(after) = state.get_heads(&book_id) {
+                state.record_edit(book_id, Edit { before, after });
+            }
+        }
+        LocalContactsRequest::MarkVisited(book_id) => {
+            state
+                .mark_visited(book_id)
+                .ok_or(UpdateError::not_found("book", &book_id.to_string()))?;
+        }
+        LocalContactsRequest::Backup => {
+            let name = backups::save(our, &state.archive()?)?;
+            println!("saved backup {}", name);
+        }
+        LocalContactsRequest::RestoreBackup(name, mode) => {
+            let archive = backups::load(our, &name)?;
+            state.restore(our, archive, &mode)?;
+            println!("restored backup {}", name);
+        }
+        LocalContactsRequest::Undo(book_id) => {
+            let Some(edit) = state.last_undo(&book_id) else {
+                return Err(UpdateError::invalid("undo", "nothing to undo").into());
+            };
+            let undo = revert_edit(our, book_id, &edit, state)?;
+            state.mark_undone(&book_id, undo);
+        }
+        LocalContactsRequest::Redo(book_id) => {
+            // redoing an edit is reverting its undo
+            let Some(undo) = state.last_redo(&book_id) else {
+                return Err(UpdateError::invalid("redo", "nothing to redo").into());
+            };
+            let redo = revert_edit(our, book_id, &undo, state)?;
+            state.mark_redone(&book_id, redo);
+        }
+        LocalContactsRequest::UpdateLocal(book_id, contact_id, local_update) => {
+            let Some(crdt) = state.get_book_mut(&book_id) else {
+                return Err(UpdateError::not_found("book", &book_id.to_string()).into());
+            };
+            let contact_book: ContactBook = hydrate(crdt)?;
+            if !contact_book.contacts.contains_key(&contact_id) {
+                return Err(UpdateError::not_found("contact", &contact_id).into());
+            }
+            let meta = state.get_local_meta_mut(book_id, contact_id);
+            match local_update {
+                LocalUpdate::SetFavorite(favorite) => meta.favorite = favorite,
+                LocalUpdate::MarkViewed => meta.last_viewed = Some(now()),
+                LocalUpdate::SetNotes(notes) => meta.notes = notes,
+            }
         }
         LocalContactsRequest::NewBook(name) => {
+            contact_book::check_id("book name", &name)?;
             let book_id = Uuid::new_v4();
             let mut crdt = AutoCommit::default();
             let contact_book = ContactBook::new(name, our);
@@ -128,7 +227,7 @@ fn handle_local_request(
         }
         LocalContactsRequest::CreateInvite(book_id, address, status) => {
             let Some(crdt) = state.get_book_mut(&book_id) else {
-                return Err(anyhow::anyhow!("book not found"));
+                return Err(UpdateError::not_found("book", &book_id.to_string()).into());
             };
             let contact_book: ContactBook = hydrate(crdt)?;
             let data = crdt.save();
@@ -144,12 +243,78 @@ fn handle_local_request(
                 .expects_response(TIMEOUT)
                 .send()?;
         }
+        LocalContactsRequest::InviteContact(book_id, contact_id, invite_book_id, status) => {
+            let Some(crdt) = state.get_book_mut(&book_id) else {
+                return Err(UpdateError::not_found("book", &book_id.to_string()).into());
+            };
+            let contact_book: ContactBook = hydrate(crdt)?;
+            let address = contact_address(our, &contact_book, &contact_id)?;
+            return handle_local_request(
+                our,
+                LocalContactsRequest::CreateInvite(invite_book_id, address, status),
+                state,
+            );
+        }
+        LocalContactsRequest::ShareContactWith(book_id, contact_id, recipient_id) => {
+            let Some(crdt) = state.get_book_mut(&book_id) else {
+                return Err(UpdateError::not_found("book", &book_id.to_string()).into());
+            };
+            let contact_book: ContactBook = hydrate(crdt)?;
+            let address = contact_address(our, &contact_book, &recipient_id)?;
+            return handle_local_request(
+                our,
+                LocalContactsRequest::ShareContact(book_id, contact_id, address),
+                state,
+            );
+        }
+        LocalContactsRequest::ShareContact(book_id, contact_id, address) => {
+            let Some(crdt) = state.get_book_mut(&book_id) else {
+                return Err(UpdateError::not_found("book", &book_id.to_string()).into());
+            };
+            let contact_book: ContactBook = hydrate(crdt)?;
+            let Some(contact) = contact_book.contacts.get(&contact_id) else {
+                return Err(UpdateError::not_found("contact", &contact_id).into());
+            };
+            // organizations and blobs only make sense within this book
+            let contact = Contact {
+                organization: None,
+                avatar: None,
+                attachments: BTreeMap::new(),
+                ..contact.clone()
+            };
+            Request::to(&address)
+                .body(serde_json::to_vec(&RemoteContactsRequest::ContactCard {
+                    id: contact_id,
+                    contact,
+                })?)
+                .context(address.to_string())
+                .expects_response(TIMEOUT)
+                .send()?;
+        }
+        LocalContactsRequest::ImportContactCard(card_id, book_id) => {
+            let Some(card) = state.get_cards().get(&card_id) else {
+                return Err(UpdateError::not_found("contact card", &card_id.to_string()).into());
+            };
+            let update = Update::AddContact(card.id.clone(), card.contact.clone());
+            // keep the card if the import is rejected, so it can be retried
+            handle_update(our, book_id, update, state)?;
+            state.remove_card(&card_id);
+        }
+        LocalContactsRequest::DismissContactCard(card_id) => {
+            state
+                .remove_card(&card_id)
+                .ok_or(UpdateError::not_found("contact card", &card_id.to_string()))?;
+        }
         LocalContactsRequest::AcceptInvite(book_id) => {
-            let Some(invite) = state.remove_invite(&book_id) else {
-                return Err(anyhow::anyhow!("invite not found"));
+            let Some(invite) = state.get_invites().get(&book_id) else {
+                return Err(UpdateError::not_found("invite", &book_id.to_string()).into());
             };
 
-            state.add_book(book_id, AutoCommit::load(&invite.data)?);
+            // keep the invite if the book can't be loaded
+            let mut crdt = AutoCommit::load(&invite.data)?;
+            migrations::migrate(&mut crdt)?;
+            let invite = state.remove_invite(&book_id).unwrap();
+            state.add_book(book_id, crdt);
 
             Request::to(&invite.from)
                 .body(serde_json::to_vec(
@@ -164,7 +329,7 @@ fn handle_local_request(
         }
         LocalContactsRequest::RejectInvite(book_id) => {
             let Some(invite) = state.remove_invite(&book_id) else {
-                return Err(anyhow::anyhow!("invite not found"));
+                return Err(UpdateError::not_found("invite", &book_id.to_string()).into());
             };
 
             Request::to(&invite.from)
@@ -189,26 +354,88 @@ fn handle_update(
     state: &mut State,
 ) -> anyhow::Result<()> {
     let Some(crdt) = state.get_book_mut(&book_id) else {
-        return Err(anyhow::anyhow!("book not found"));
+        return Err(UpdateError::not_found("book", &book_id.to_string()).into());
     };
 
-    let removed = match &update {
-        Update::RemovePeer(address) => Some(address.clone()),
-        _ => None,
-    };
+    let removed: Vec<Address> = update
+        .operations()
+        .into_iter()
+        .filter_map(|update| match update {
+            Update::RemovePeer(address) => Some(address.clone()),
+            _ => None,
+        })
+        .collect();
+
+    // restoring a past version reads the book's history, which commits
+    // anything pending, so it must happen before we start writing
+    let update = history::resolve_versions(crdt, update)?;
+
+    // clearing a conflict takes a write that supersedes every concurrent value,
+    // which reconciling the hydrated book won't necessarily produce
+    for update in update.operations() {
+        if let Update::ResolveConflict(id, field, value) = update
+            && let Err(e) = conflicts::resolve(crdt, id, field, value)
+        {
+            crdt.rollback();
+            return Err(e);
+        }
+    }
 
     let mut contact_book: ContactBook = hydrate(crdt)?;
-    contact_book.apply_update(update)?;
+    let summary = history::describe(&contact_book, our, &update);
+    if let Err(e) = contact_book.apply_update(our, update) {
+        // drop any direct writes above, so a failed update leaves no trace
+        crdt.rollback();
+        return Err(e.into());
+    }
+    contact_book.purge_trash(now());
 
     // if we just removed ourself, as the owner, set a new owner
     // so that the book is not stuck
-    if removed == Some(our.clone()) && contact_book.peers.len() > 0 {
+    if removed.contains(our) && contact_book.peers.len() > 0 {
         let new_owner = contact_book.peers.keys().next().unwrap().clone();
         contact_book.owner = new_owner.parse()?;
     }
 
     reconcile(crdt, &contact_book).unwrap();
+    history::commit(crdt, our, summary)?;
+
+    send_sync(our, book_id, crdt, &contact_book, removed)?;
+    state.refresh_conflicts(&book_id);
+    Ok(())
+}
+
+/// Revert one of our edits to a book as a new change, synced to peers like
+/// any other, and return that change as an `Edit` so it can be reverted in
+/// turn.
+fn revert_edit(
+    our: &Address,
+    book_id: Uuid,
+    edit: &Edit,
+    state: &mut State,
+) -> anyhow::Result<Edit> {
+    let Some(crdt) = state.get_book_mut(&book_id) else {
+        return Err(UpdateError::not_found("book", &book_id.to_string()).into());
+    };
+    let before = crdt.get_heads();
+    history::revert(crdt, our, &edit.before, &edit.after)?;
+    let after = crdt.get_heads();
+
+    let contact_book: ContactBook = hydrate(crdt)?;
+    send_sync(our, book_id, crdt, &contact_book, vec![])?;
+    state.refresh_conflicts(&book_id);
+    Ok(Edit { before, after })
+}
 
+/// Send the book to all its peers but us, and to `removed`, peers an update
+/// just removed, one final time.
+fn send_sync(
+    our: &Address,
+    book_id: Uuid,
+    crdt: &mut AutoCommit,
+    contact_book: &ContactBook,
+    removed: Vec<Address>,
+) -> anyhow::Result<()> {
     let sync_request = serde_json::to_vec(&RemoteContactsRequest::Sync {
         book_id,
         data: crdt.save(),
@@ -226,15 +453,15 @@ fn handle_update(
         }
     }
 
-    // if update was to remove a peer, send them the sync too, one final time
-    if let Some(address) = removed
-        && address != *our
-    {
-        Request::to(&address)
-            .body(sync_request)
-            .context(address.to_string())
-            .expects_response(TIMEOUT)
-            .send()?;
+    // if update removed any peers, send them the sync too, one final time
+    for address in removed {
+        if address != *our {
+            Request::to(&address)
+                .body(sync_request.clone())
+                .context(address.to_string())
+                .expects_response(TIMEOUT)
+                .send()?;
+        }
     }
     Ok(())
 }
@@ -257,8 +484,32 @@ fn handle_remote_message(our: &Address, message: Message, state: &mut State) ->
             let mut their_fork =
                 AutoCommit::load(&data)?.with_actor(message.source().node().as_bytes().into());
 
+            let theirs = migrations::schema_version(&their_fork)?;
+            if theirs > SCHEMA_VERSION {
+                return respond_with_err(ContactsError::IncompatibleSchema {
+                    ours: SCHEMA_VERSION,
+                    theirs,
+                });
+            }
+
             println!("merging update from {}", message.source().node());
             crdt.merge(&mut their_fork)?;
+            // peers that haven't upgraded yet may have written an older layout
+            migrations::migrate_from(crdt, theirs)?;
+
+            // fetch any avatars or attachments the merge introduced
+            let contact_book: ContactBook = hydrate(crdt)?;
+            let missing: HashSet<&String> = contact_book
+                .contacts
+                .values()
+                .flat_map(|c| c.blob_hashes())
+                .filter(|hash| !blobs::has(our, hash))
+                .collect();
+            for hash in missing {
+                request_blob(our, book_id, &contact_book, hash, state)?;
+            }
+
+            state.refresh_conflicts(&book_id);
         }
         RemoteContactsRequest::Invite {
             book_id,
@@ -266,6 +517,13 @@ fn handle_remote_message(our: &Address, message: Message, state: &mut State) ->
             status,
             data,
         } => {
+            let theirs = migrations::schema_version(&AutoCommit::load(&data)?)?;
+            if theirs > SCHEMA_VERSION {
+                return respond_with_err(ContactsError::IncompatibleSchema {
+                    ours: SCHEMA_VERSION,
+                    theirs,
+                });
+            }
             let invite = Invite {
                 from: message.source().clone(),
                 name,
@@ -288,12 +546,143 @@ fn handle_remote_message(our: &Address, message: Message, state: &mut State) ->
                 return handle_update(our, book_id, update, state);
             }
         }
+        RemoteContactsRequest::ContactCard { id, contact } => {
+            if message.body().len() > MAX_CARD_SIZE
+                || state.count_cards_from(message.source()) >= MAX_CARDS_PER_SENDER
+            {
+                return respond_with_err(ContactsError::CardRejected);
+            }
+            state.add_card(ContactCard {
+                from: message.source().clone(),
+                id,
+                contact,
+            });
+        }
+        RemoteContactsRequest::GetBlob { book_id, hash } => {
+            if !blobs::is_valid_hash(&hash) {
+                return respond_with_err(ContactsError::BlobNotFound);
+            }
+            let Some(crdt) = state.get_book_mut(&book_id) else {
+                return respond_with_err(ContactsError::BlobNotFound);
+            };
+            let contact_book: ContactBook = hydrate(crdt)?;
+            if !contact_book
+                .peers
+                .contains_key(&message.source().to_string())
+            {
+                return respond_with_err(ContactsError::UnknownPeer);
+            }
+            // only hand out blobs that the book actually refers to
+            if !contact_book.references_blob(&hash) {
+                return respond_with_err(ContactsError::BlobNotFound);
+            }
+            let Some(bytes) = blobs::load(our, &hash) else {
+                return respond_with_err(ContactsError::BlobNotFound);
+            };
+            // no context: failed blob sends aren't retried, they will be
+            // requested again the next time they're needed
+            Request::to(message.source())
+                .body(serde_json::to_vec(&RemoteContactsRequest::Blob {
+                    book_id,
+                    hash,
+                })?)
+                .blob_bytes(bytes)
+                .expects_response(TIMEOUT)
+                .send()?;
+        }
+        RemoteContactsRequest::Blob { book_id, hash } => {
+            if !blobs::is_valid_hash(&hash) {
+                return respond_with_err(ContactsError::BadBlob);
+            }
+            let Some(crdt) = state.get_book_mut(&book_id) else {
+                return respond_with_err(ContactsError::BlobNotFound);
+            };
+            let contact_book: ContactBook = hydrate(crdt)?;
+            if !contact_book
+                .peers
+                .contains_key(&message.source().to_string())
+            {
+                return respond_with_err(ContactsError::UnknownPeer);
+            }
+            if !contact_book.references_blob(&hash) {
+                return respond_with_err(ContactsError::BlobNotFound);
+            }
+            let Some(blob) = kinode_process_lib::get_blob() else {
+                return respond_with_err(ContactsError::BadBlob);
+            };
+            if blobs::hash(&blob.bytes) != hash {
+                return respond_with_err(ContactsError::BadBlob);
+            }
+            blobs::save(our, &blob.bytes)?;
+            state.mark_blob_received(&hash);
+        }
     }
     Response::new()
         .body(serde_json::to_vec(&ContactsResponse::Ok(()))?)
         .send()
 }
 
+/// Our own process on a contact's node, for contacts who are Kinode users:
+/// they're running this same app.
+fn contact_address(
+    our: &Address,
+    book: &ContactBook,
+    contact_id: &str,
+) -> Result<Address, UpdateError> {
+    let contact = book
+        .contacts
+        .get(contact_id)
+        .ok_or(UpdateError::not_found("contact", contact_id))?;
+    let Some(node) = &contact.node else {
+        return Err(UpdateError::invalid("contact", "has no node"));
+    };
+    Ok(Address::new(node, our.process.clone()))
+}
+
+/// Ask the peers of a book for a blob we're missing, each only once:
+/// a peer that has it sends it, the others may get it later themselves.
+fn request_blob(
+    our: &Address,
+    book_id: Uuid,
+    book: &ContactBook,
+    hash: &str,
+    state: &mut State,
+) -> anyhow::Result<()> {
+    let body = serde_json::to_vec(&RemoteContactsRequest::GetBlob {
+        book_id,
+        hash: hash.to_string(),
+    })?;
+    for peer in book.peers.keys() {
+        let peer: Address = peer.parse()?;
+        if &peer == our || !state.mark_blob_requested(hash, &peer) {
+            continue;
+        }
+        Request::to(&peer)
+            .body(body.clone())
+            .expects_response(TIMEOUT)
+            .send()?;
+    }
+    Ok(())
+}
+
+/// Purge the trash of every book we can edit that has expired contacts in
+/// it, so that the retention period holds even for books nobody edits.
+fn purge_expired_trash(our: &Address, state: &mut State) {
+    let now = now();
+    for (book_id, book) in state.get_books_hydrated() {
+        let can_edit = matches!(
+            book.peers.get(&our.to_string()),
+            Some(PeerStatus::Owner | PeerStatus::ReadWrite)
+        );
+        if !can_edit || !book.has_expired_trash(now) {
+            continue;
+        }
+        if let Err(e) = handle_update(our, book_id, Update::PurgeTrash, state) {
+            println!("failed to purge the trash of book {}: {:?}", book_id, e);
+        }
+    }
+}
+
 fn respond_with_err(err: ContactsError) -> anyhow::Result<()> {
     Response::new()
         .body(serde_json::to_vec(&ContactsResponse::Err(err))?)
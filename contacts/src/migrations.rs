@@ -0,0 +1,256 @@
+//! Schema versioning for contact book documents. Every book records the
+//! schema version it was last written with; older documents are migrated
+//! in place when loaded and after every merge, since a peer that hasn't
+//! upgraded yet may still write the old layout.
+use automerge::{
+    transaction::{CommitOptions, Transactable},
+    ActorId, AutoCommit, ChangeHash, ObjId, ObjType, ReadDoc, ScalarValue, Value,
+};
+use sha2::{Digest, Sha256};
+
+/// The schema version this build of the app reads and writes.
+/// Bump this and add a step to `MIGRATIONS` whenever `ContactBook`,
+/// `Contact`, or any type stored inside them changes in a way that
+/// older documents can't be hydrated into.
+pub const SCHEMA_VERSION: u64 = 2;
+
+/// `MIGRATIONS[n]` upgrades a document from version `n` to `n + 1`.
+/// Steps must be idempotent: they are re-run after merging changes from
+/// peers on an older version, over a document that may already be upgraded.
+const MIGRATIONS: [fn(&mut AutoCommit) -> anyhow::Result<()>; SCHEMA_VERSION as usize] =
+    [descriptions_to_text, normalize_socials];
+
+/// Version 0 documents have no `schema_version` field.
+pub fn schema_version(doc: &AutoCommit) -> anyhow::Result<u64> {
+    Ok(
+        autosurgeon::hydrate_prop::<_, Option<u64>, _, _>(doc, &automerge::ROOT, "schema_version")?
+            .unwrap_or(0),
+    )
+}
+
+/// Bring a document up to `SCHEMA_VERSION`. Fails if the document was
+/// written by a newer version of the app, which we can't safely read.
+pub fn migrate(doc: &mut AutoCommit) -> anyhow::Result<()> {
+    let version = schema_version(doc)?;
+    migrate_from(doc, version)
+}
+
+/// Run every migration step from `version` onwards. After a merge, pass
+/// the version of the peer whose changes were merged in.
+///
+/// Every peer migrates a book on its own, so the steps are committed as one
+/// change that is identical on all peers that had the same heads: made by an
+/// actor derived from those heads, at time zero. Merged, such changes are
+/// one and the same, rather than concurrent edits of which only one wins,
+/// such as two new text objects for the same description.
+pub fn migrate_from(doc: &mut AutoCommit, version: u64) -> anyhow::Result<()> {
+    if version > SCHEMA_VERSION {
+        return Err(anyhow::anyhow!(
+            "book has schema version {}, newer than our {}",
+            version,
+            SCHEMA_VERSION
+        ));
+    }
+    let actor = doc.get_actor().clone();
+    let heads = doc.get_heads();
+    doc.set_actor(migration_actor(&heads, version));
+    let result = run_migrations(doc, version);
+    match result {
+        Ok(()) => {
+            doc.commit_with(CommitOptions::default().with_time(0));
+        }
+        Err(_) => {
+            doc.rollback();
+        }
+    }
+    doc.set_actor(actor);
+    result
+}
+
+fn run_migrations(doc: &mut AutoCommit, version: u64) -> anyhow::Result<()> {
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(doc)?;
+    }
+    if schema_version(doc)? < SCHEMA_VERSION {
+        autosurgeon::reconcile_prop(doc, automerge::ROOT, "schema_version", SCHEMA_VERSION)?;
+    }
+    Ok(())
+}
+
+/// The actor migrations from `version` are made by, on a document with `heads`.
+fn migration_actor(heads: &[ChangeHash], version: u64) -> ActorId {
+    let mut heads = heads.to_vec();
+    heads.sort();
+    let mut hasher = Sha256::new();
+    hasher.update(b"contacts schema migration");
+    hasher.update(version.to_be_bytes());
+    for head in &heads {
+        hasher.update(head.0);
+    }
+    ActorId::from(&hasher.finalize()[..16])
+}
+
+/// 0 -> 1: contact descriptions were plain strings, now they are text objects.
+fn descriptions_to_text(doc: &mut AutoCommit) -> anyhow::Result<()> {
+    let Some((_, contacts)) = doc.get(automerge::ROOT, "contacts")? else {
+        return Ok(());
+    };
+    let ids: Vec<String> = doc.keys(&contacts).collect();
+    for id in ids {
+        let Some((_, contact)) = doc.get(&contacts, id.as_str())? else {
+            continue;
+        };
+        let Some((Value::Scalar(value), _)) = doc.get(&contact, "description")? else {
+            continue;
+        };
+        let ScalarValue::Str(description) = value.as_ref() else {
+            continue;
+        };
+        let description = description.to_string();
+        let text = doc.put_object(&contact, "description", ObjType::Text)?;
+        doc.splice_text(&text, 0, 0, &description)?;
+    }
+    Ok(())
+}
+
+/// 1 -> 2: social keys and values are stored in the canonical form from
+/// `socials`. Values a known network rejects are left as they were, and a
+/// value whose canonical key is already taken stays under its old key.
+fn normalize_socials(doc: &mut AutoCommit) -> anyhow::Result<()> {
+    for collection in ["contacts", "organizations"] {
+        let Some((_, items)) = doc.get(automerge::ROOT, collection)? else {
+            continue;
+        };
+        let ids: Vec<String> = doc.keys(&items).collect();
+        for id in ids {
+            let Some((_, item)) = doc.get(&items, id.as_str())? else {
+                continue;
+            };
+            if let Some((_, socials)) = doc.get(&item, "socials")? {
+                normalize_socials_in(doc, &socials)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn normalize_socials_in(doc: &mut AutoCommit, socials: &ObjId) -> anyhow::Result<()> {
+    let keys: Vec<String> = doc.keys(socials).collect();
+    for key in keys {
+        let Some((Value::Scalar(value), _)) = doc.get(socials, key.as_str())? else {
+            continue;
+        };
+        let ScalarValue::Str(value) = value.as_ref() else {
+            continue;
+        };
+        let value = value.to_string();
+        let Ok((new_key, new_value)) = crate::socials::normalize(&key, &value) else {
+            continue;
+        };
+        if new_key != key && doc.get(socials, new_key.as_str())?.is_none() {
+            doc.delete(socials, key.as_str())?;
+            doc.put(socials, new_key.as_str(), new_value)?;
+        } else if new_value != value {
+            doc.put(socials, key.as_str(), new_value)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contact_book::{Contact, ContactBook};
+    use crate::testing::our;
+    use autosurgeon::{hydrate, reconcile};
+
+    /// A book as version 0 wrote it, with `alice`'s description as a string.
+    fn unversioned_book() -> AutoCommit {
+        let mut book = ContactBook::new("Test".to_string(), &our());
+        book.contacts
+            .insert("alice".to_string(), Contact::default());
+        let mut doc = AutoCommit::new();
+        reconcile(&mut doc, &book).unwrap();
+        doc.delete(automerge::ROOT, "schema_version").unwrap();
+        let (_, contacts) = doc.get(automerge::ROOT, "contacts").unwrap().unwrap();
+        let (_, alice) = doc.get(&contacts, "alice").unwrap().unwrap();
+        doc.put(&alice, "description", "hello world").unwrap();
+        doc
+    }
+
+    #[test]
+    fn descriptions_become_text() {
+        let mut doc = unversioned_book();
+        assert_eq!(schema_version(&doc).unwrap(), 0);
+        migrate(&mut doc).unwrap();
+        assert_eq!(schema_version(&doc).unwrap(), SCHEMA_VERSION);
+        let book: ContactBook = hydrate(&doc).unwrap();
+        let description = book.contacts["alice"].description.as_ref().unwrap();
+        assert_eq!(description.as_str(), "hello world");
+    }
+
+    #[test]
+    fn peers_migrate_to_the_same_change() {
+        let mut ours = unversioned_book();
+        let mut theirs = ours.fork();
+        migrate(&mut ours).unwrap();
+        migrate(&mut theirs).unwrap();
+        assert_eq!(ours.get_heads(), theirs.get_heads());
+
+        // so a splice either of us makes after lands in the one description
+        let mut book: ContactBook = hydrate(&theirs).unwrap();
+        let description = book.contacts.get_mut("alice").unwrap().description.as_mut();
+        description.unwrap().splice(5, 0, ",");
+        reconcile(&mut theirs, &book).unwrap();
+        ours.merge(&mut theirs).unwrap();
+        let (_, contacts) = ours.get(automerge::ROOT, "contacts").unwrap().unwrap();
+        let (_, alice) = ours.get(&contacts, "alice").unwrap().unwrap();
+        assert_eq!(ours.get_all(&alice, "description").unwrap().len(), 1);
+        let book: ContactBook = hydrate(&ours).unwrap();
+        let description = book.contacts["alice"].description.as_ref().unwrap();
+        assert_eq!(description.as_str(), "hello, world");
+    }
+
+    #[test]
+    fn migrating_again_changes_nothing() {
+        let mut doc = unversioned_book();
+        migrate(&mut doc).unwrap();
+        let heads = doc.get_heads();
+        migrate_from(&mut doc, 0).unwrap();
+        assert_eq!(doc.get_heads(), heads);
+    }
+
+    #[test]
+    fn newer_books_are_refused() {
+        let mut doc = unversioned_book();
+        autosurgeon::reconcile_prop(
+            &mut doc,
+            automerge::ROOT,
+            "schema_version",
+            SCHEMA_VERSION + 1,
+        )
+        .unwrap();
+        assert!(migrate(&mut doc).is_err());
+    }
+
+    #[test]
+    fn socials_are_normalized_without_losing_any() {
+        let mut doc = unversioned_book();
+        let (_, contacts) = doc.get(automerge::ROOT, "contacts").unwrap().unwrap();
+        let (_, alice) = doc.get(&contacts, "alice").unwrap().unwrap();
+        let (_, socials) = doc.get(&alice, "socials").unwrap().unwrap();
+        doc.put(&socials, "twitter", "https://x.com/alice").unwrap();
+        doc.put(&socials, "X", "@not_alice").unwrap();
+        doc.put(&socials, "Mail", "Alice@Example.com").unwrap();
+        doc.put(&socials, "github", "not a handle").unwrap();
+        migrate(&mut doc).unwrap();
+
+        let book: ContactBook = hydrate(&doc).unwrap();
+        let socials = &book.contacts["alice"].socials;
+        assert_eq!(socials["twitter"], "https://x.com/alice");
+        assert_eq!(socials["X"], "https://x.com/not_alice");
+        assert_eq!(socials["email"], "alice@example.com");
+        assert_eq!(socials["github"], "not a handle");
+        assert_eq!(socials.len(), 4);
+    }
+}
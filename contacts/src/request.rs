@@ -1,4 +1,10 @@
-use crate::contact_book::{Contact, PeerStatus};
+use crate::{
+    conflicts::ConflictField,
+    contact_book::{
+        Attachment, BookSettings, Contact, Deal, Organization, PeerStatus, Relationship, Task,
+    },
+};
+use automerge::ChangeHash;
 use kinode_process_lib::Address;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -17,21 +23,138 @@ pub enum LocalContactsRequest {
     CreateInvite(Uuid, Address, PeerStatus),
     AcceptInvite(Uuid),
     RejectInvite(Uuid),
+    /// Invite a contact with a Kinode node to one of our books:
+    /// (book the contact is in, contact, book to invite them to, status).
+    InviteContact(Uuid, String, Uuid, PeerStatus),
+    /// Send one contact from a book to another node, who need not be a peer of the book.
+    ShareContact(Uuid, String, Address),
+    /// Send one contact to another contact with a Kinode node, like
+    /// `ShareContact`: (book both are in, contact, contact to send it to).
+    ShareContactWith(Uuid, String, String),
+    /// Import a contact card someone shared with us: (card, book to import into).
+    ImportContactCard(Uuid, Uuid),
+    DismissContactCard(Uuid),
     Update(Uuid, Update),
+    /// Change our own metadata about a contact: (book, contact, change).
+    /// This stays on our node and is never synced to the book's peers.
+    UpdateLocal(Uuid, String, LocalUpdate),
+    /// Revert our most recent `Update` to the book that hasn't been undone,
+    /// as a new change that syncs to peers like any other.
+    Undo(Uuid),
+    /// Reapply the edit most recently undone with `Undo`.
+    Redo(Uuid),
+    /// Note that we've seen the book as it is now. `/books/:id/diff`
+    /// shows what changed since.
+    MarkVisited(Uuid),
+    /// Save a backup of all our books, invites and local metadata now,
+    /// besides the scheduled daily ones. `/backups` lists them.
+    Backup,
+    /// Load a backup by name, merging it with our books or replacing them.
+    RestoreBackup(String, RestoreMode),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RestoreMode {
+    /// Keep what we have and add what the backup has on top.
+    Merge,
+    /// Go back to exactly what the backup has. Only for books that aren't
+    /// shared: fails if any of ours has other peers.
+    Replace,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum LocalUpdate {
+    SetFavorite(bool),
+    /// Record that we just looked at the contact.
+    MarkViewed,
+    SetNotes(Option<String>),
 }
 
 /// Only used locally. This is how we modify an existing book.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Update {
     AddContact(String, Contact),
+    /// Moves the contact to the book's trash, from which it can be restored
+    /// until it is purged.
     RemoveContact(String),
+    RestoreContact(String),
+    /// Permanently delete a contact from the trash before it expires, e.g.
+    /// to make room for removing another contact with the same ID.
+    DeleteFromTrash(String),
+    /// Bring a contact back to how it was as of the given heads, as a new
+    /// change that syncs like any other. Works for removed contacts too.
+    /// See `/books/:id/at` for viewing past versions.
+    RestoreContactVersion(String, Vec<ChangeHash>),
+    /// This should not be used by frontend. The backend replaces a
+    /// `RestoreContactVersion` with this once it has read the past version.
+    PutContact(String, Contact),
+    /// (keep, merge): fold a duplicate contact into another. The merged
+    /// contact is moved to the trash and recorded in the kept one's
+    /// `merged_from`.
+    MergeContacts(String, String),
+    /// Replace a contact's description. The new value is diffed against the
+    /// old one, so prefer `SpliceContactDescription` where the edit is known.
     EditContactDescription(String, String),
+    /// Character-level edit of a contact's description: (contact, position,
+    /// number of characters to delete, text to insert). Positions count
+    /// unicode characters, not bytes.
+    SpliceContactDescription(String, usize, usize, String),
+    /// Settle a field that peers edited concurrently by choosing its value:
+    /// (contact, field, value). See `/state` for current conflicts.
+    ResolveConflict(String, ConflictField, String),
     EditContactSocial(String, String, String),
     RemoveContactSocial(String, String),
+    /// Set or clear a contact's Kinode node name. Must be a valid node id.
+    SetContactNode(String, Option<String>),
+    /// (contact, blob hash). Set by the backend after an upload to `/attachment`.
+    SetContactAvatar(String, Option<String>),
+    /// (contact, blob hash, attachment). Set by the backend after an upload to `/attachment`.
+    AddContactAttachment(String, String, Attachment),
+    RemoveContactAttachment(String, String),
+    AddOrganization(String, Organization),
+    /// Replace all fields of an existing organization.
+    EditOrganization(String, Organization),
+    /// Also unlinks every contact that belonged to the organization.
+    RemoveOrganization(String),
+    /// (contact, organization). A contact belongs to at most one organization.
+    LinkContactToOrganization(String, String),
+    UnlinkContactFromOrganization(String),
+    /// Replace the book's ordered list of pipeline stages. Fails if any
+    /// deal is in a stage that is not in the new list.
+    SetDealStages(Vec<String>),
+    AddDeal(String, Deal),
+    /// (deal, stage)
+    MoveDeal(String, String),
+    RemoveDeal(String),
+    AddTask(String, Task),
+    SetTaskDone(String, bool),
+    RemoveTask(String),
+    AddRelationship(Relationship),
+    RemoveRelationship(Relationship),
+    /// Rename the book and change its other settings. Owner only.
+    SetBookSettings(BookSettings),
+    /// Permanently delete contacts that have been in the trash for longer
+    /// than `TRASH_RETENTION`. Every update does this too, and the backend
+    /// sends it daily so that books nobody edits are purged as well.
+    PurgeTrash,
     /// This should not be used by frontend. User should create invite,
     /// then when invite has been accepted, backend will perform this action.
     AddPeer(Address, PeerStatus),
     RemovePeer(Address),
+    /// Apply several updates as a single change. If any of them fails,
+    /// none are applied. Batches cannot be nested.
+    Batch(Vec<Update>),
+}
+
+impl Update {
+    /// The individual operations in this update: the update itself,
+    /// or each of a batch's updates.
+    pub fn operations(&self) -> Vec<&Update> {
+        match self {
+            Update::Batch(updates) => updates.iter().collect(),
+            update => vec![update],
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -52,4 +175,21 @@ pub enum RemoteContactsRequest {
         book_id: Uuid,
         accepted: bool,
     },
+    /// A single contact, shared with us by `ShareContact`. Lands in our
+    /// pending cards until we import or dismiss it.
+    ContactCard {
+        id: String,
+        contact: Contact,
+    },
+    /// Ask a peer for an avatar or attachment referenced in a book that we
+    /// don't have. They reply with a `Blob` request.
+    GetBlob {
+        book_id: Uuid,
+        hash: String,
+    },
+    /// The bytes of the blob are sent as the lazy-load blob of this request.
+    Blob {
+        book_id: Uuid,
+        hash: String,
+    },
 }
@@ -7,4 +7,86 @@ pub enum ContactsError {
     UnknownPeer,
     ReadOnlyPeer,
     BadSync,
+    BlobNotFound,
+    BadBlob,
+    /// The contact card is too large, or we already hold too many from the sender.
+    CardRejected,
+    /// The book was written by a newer version of the app than ours.
+    IncompatibleSchema {
+        ours: u64,
+        theirs: u64,
+    },
 }
+
+/// Why a local request, usually an `Update`, was rejected. Sent back to
+/// the UI as JSON from `/post`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum UpdateError {
+    /// A name or ID that identifies something was empty.
+    EmptyId {
+        field: String,
+    },
+    TooLong {
+        field: String,
+        max: usize,
+    },
+    /// `kind` is what was looked for: "contact", "book", "deal"...
+    NotFound {
+        kind: String,
+        id: String,
+    },
+    Duplicate {
+        kind: String,
+        id: String,
+    },
+    PermissionDenied(String),
+    Invalid {
+        field: String,
+        reason: String,
+    },
+    /// The index and error of every operation in an `Update::Batch` that
+    /// failed. Nothing in the batch was applied.
+    Batch(Vec<(usize, UpdateError)>),
+}
+
+impl UpdateError {
+    pub fn not_found(kind: &str, id: &str) -> Self {
+        UpdateError::NotFound {
+            kind: kind.to_string(),
+            id: id.to_string(),
+        }
+    }
+
+    pub fn invalid(field: &str, reason: impl ToString) -> Self {
+        UpdateError::Invalid {
+            field: field.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpdateError::EmptyId { field } => write!(f, "{} cannot be empty", field),
+            UpdateError::TooLong { field, max } => {
+                write!(f, "{} is longer than {} characters", field, max)
+            }
+            UpdateError::NotFound { kind, id } => write!(f, "{} not found: {}", kind, id),
+            UpdateError::Duplicate { kind, id } => {
+                write!(f, "{} already exists: {}", kind, id)
+            }
+            UpdateError::PermissionDenied(reason) => write!(f, "permission denied: {}", reason),
+            UpdateError::Invalid { field, reason } => write!(f, "invalid {}: {}", field, reason),
+            UpdateError::Batch(errors) => {
+                write!(f, "{} operation(s) in batch failed", errors.len())?;
+                for (index, error) in errors {
+                    write!(f, "; {}: {}", index, error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for UpdateError {}
@@ -0,0 +1,284 @@
+//! The social networks we know about. Keys like "Twitter", "X" and "x.com"
+//! all name the same network, and a profile can be given as a handle or as
+//! any of several URLs, so both are normalized to one canonical form before
+//! being stored. Keys we don't know are kept as written.
+use crate::{contact_book::parse_node_id, response::UpdateError};
+use std::collections::BTreeMap;
+
+struct Network {
+    /// The key values are stored under.
+    key: &'static str,
+    /// Other names for the network, compared case-insensitively.
+    aliases: &'static [&'static str],
+    normalize: fn(&str) -> anyhow::Result<String>,
+}
+
+const NETWORKS: &[Network] = &[
+    Network {
+        key: "twitter",
+        aliases: &["x", "x.com", "twitter.com"],
+        normalize: twitter,
+    },
+    Network {
+        key: "telegram",
+        aliases: &["tg", "t.me"],
+        normalize: telegram,
+    },
+    Network {
+        key: "github",
+        aliases: &["gh", "github.com"],
+        normalize: github,
+    },
+    Network {
+        key: "linkedin",
+        aliases: &["linkedin.com"],
+        normalize: linkedin,
+    },
+    Network {
+        key: "email",
+        aliases: &["e-mail", "mail"],
+        normalize: email,
+    },
+    Network {
+        key: "phone",
+        aliases: &["tel", "telephone", "mobile", "cell"],
+        normalize: phone,
+    },
+    Network {
+        key: "kinode",
+        aliases: &["node", "kinode node"],
+        normalize: kinode,
+    },
+    Network {
+        key: "website",
+        aliases: &["web", "site", "url", "homepage"],
+        normalize: website,
+    },
+];
+
+/// The canonical key for `key`, or `key` itself, trimmed, if it isn't a
+/// network we know.
+pub fn normalize_key(key: &str) -> String {
+    match network(key) {
+        Some(network) => network.key.to_string(),
+        None => key.trim().to_string(),
+    }
+}
+
+/// The canonical key and value for a social. Fails if the value isn't
+/// valid for a known network.
+pub fn normalize(key: &str, value: &str) -> anyhow::Result<(String, String)> {
+    let value = value.trim();
+    if key.trim().is_empty() {
+        return Err(anyhow::anyhow!("social name cannot be empty"));
+    }
+    if value.is_empty() {
+        return Err(anyhow::anyhow!("social value cannot be empty"));
+    }
+    match network(key) {
+        Some(network) => Ok((network.key.to_string(), (network.normalize)(value)?)),
+        None => Ok((key.trim().to_string(), value.to_string())),
+    }
+}
+
+/// Normalize every social in `socials`. Fails if two of them are for the
+/// same network, e.g. "twitter" and "X", rather than dropping one.
+pub fn normalize_all(
+    socials: BTreeMap<String, String>,
+) -> Result<BTreeMap<String, String>, UpdateError> {
+    let mut normalized = BTreeMap::new();
+    for (key, value) in socials {
+        let (key, value) =
+            normalize(&key, &value).map_err(|e| UpdateError::invalid("social", e))?;
+        if normalized.contains_key(&key) {
+            return Err(UpdateError::Duplicate {
+                kind: "social".to_string(),
+                id: key,
+            });
+        }
+        normalized.insert(key, value);
+    }
+    Ok(normalized)
+}
+
+fn network(key: &str) -> Option<&'static Network> {
+    let key = key.trim().to_lowercase();
+    NETWORKS
+        .iter()
+        .find(|network| network.key == key || network.aliases.contains(&key.as_str()))
+}
+
+/// The handle in `value`, which may be given as `@handle`, `handle`, or a
+/// profile URL on one of `domains`, optionally under `path`.
+fn handle(value: &str, domains: &[&str], path: &str) -> String {
+    let value = value
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("www.");
+    let value = domains
+        .iter()
+        .find_map(|domain| value.strip_prefix(&format!("{}/", domain)))
+        .map(|rest| rest.trim_start_matches(path))
+        .unwrap_or(value);
+    value
+        .split(['?', '#', '/'])
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches('@')
+        .to_string()
+}
+
+fn check_handle(
+    network: &str,
+    handle: &str,
+    max_len: usize,
+    valid: fn(char) -> bool,
+) -> anyhow::Result<()> {
+    if handle.is_empty() || handle.chars().count() > max_len || !handle.chars().all(valid) {
+        return Err(anyhow::anyhow!("invalid {} handle: {}", network, handle));
+    }
+    Ok(())
+}
+
+fn twitter(value: &str) -> anyhow::Result<String> {
+    let handle = handle(value, &["x.com", "twitter.com"], "");
+    check_handle("twitter", &handle, 15, |c| {
+        c.is_ascii_alphanumeric() || c == '_'
+    })?;
+    Ok(format!("https://x.com/{}", handle))
+}
+
+fn telegram(value: &str) -> anyhow::Result<String> {
+    let handle = handle(value, &["t.me", "telegram.me"], "");
+    check_handle("telegram", &handle, 32, |c| {
+        c.is_ascii_alphanumeric() || c == '_'
+    })?;
+    Ok(format!("https://t.me/{}", handle))
+}
+
+fn github(value: &str) -> anyhow::Result<String> {
+    let handle = handle(value, &["github.com"], "");
+    check_handle("github", &handle, 39, |c| {
+        c.is_ascii_alphanumeric() || c == '-'
+    })?;
+    Ok(format!("https://github.com/{}", handle))
+}
+
+fn linkedin(value: &str) -> anyhow::Result<String> {
+    let handle = handle(value, &["linkedin.com"], "in/");
+    check_handle("linkedin", &handle, 100, |c| {
+        c.is_alphanumeric() || c == '-' || c == '_' || c == '%'
+    })?;
+    Ok(format!("https://www.linkedin.com/in/{}", handle))
+}
+
+fn email(value: &str) -> anyhow::Result<String> {
+    let email = value.trim_start_matches("mailto:").to_lowercase();
+    match email.split_once('@') {
+        Some((user, domain))
+            if !user.is_empty()
+                && domain.contains('.')
+                && !domain.contains('@')
+                && !email.contains(char::is_whitespace) =>
+        {
+            Ok(email)
+        }
+        _ => Err(anyhow::anyhow!("invalid email: {}", value)),
+    }
+}
+
+/// Digits only, keeping a leading `+` for international numbers.
+fn phone(value: &str) -> anyhow::Result<String> {
+    let value = value.trim_start_matches("tel:");
+    if !value
+        .chars()
+        .all(|c| c.is_ascii_digit() || " +-().".contains(c))
+    {
+        return Err(anyhow::anyhow!("invalid phone number: {}", value));
+    }
+    let digits: String = value.chars().filter(|c| c.is_ascii_digit()).collect();
+    if digits.is_empty() {
+        return Err(anyhow::anyhow!("invalid phone number: {}", value));
+    }
+    match value.trim_start().starts_with('+') {
+        true => Ok(format!("+{}", digits)),
+        false => Ok(digits),
+    }
+}
+
+fn kinode(value: &str) -> anyhow::Result<String> {
+    parse_node_id(value)
+}
+
+fn website(value: &str) -> anyhow::Result<String> {
+    if value.contains(char::is_whitespace) || !value.contains('.') {
+        return Err(anyhow::anyhow!("invalid website: {}", value));
+    }
+    let value = value.trim_end_matches('/');
+    match value.starts_with("https://") || value.starts_with("http://") {
+        true => Ok(value.to_string()),
+        false => Ok(format!("https://{}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(key: &str, value: &str) -> (String, String) {
+        normalize(key, value).unwrap()
+    }
+
+    #[test]
+    fn aliases_share_a_key() {
+        let expected = ("twitter".to_string(), "https://x.com/alice".to_string());
+        assert_eq!(normalized("Twitter", "@alice"), expected);
+        assert_eq!(normalized("X", "https://twitter.com/alice?s=20"), expected);
+        assert_eq!(normalized(" x.com ", "www.x.com/alice/"), expected);
+    }
+
+    #[test]
+    fn values_are_normalized() {
+        assert_eq!(
+            normalized("linkedin", "https://www.linkedin.com/in/alice-b/").1,
+            "https://www.linkedin.com/in/alice-b"
+        );
+        assert_eq!(
+            normalized("mail", "mailto:Alice@Example.com").1,
+            "alice@example.com"
+        );
+        assert_eq!(normalized("tel", "+1 (555) 010-2000").1, "+15550102000");
+        assert_eq!(normalized("web", "example.com/").1, "https://example.com");
+    }
+
+    #[test]
+    fn unknown_networks_are_kept_as_written() {
+        assert_eq!(
+            normalized(" Mastodon ", " @alice@example.social "),
+            ("Mastodon".to_string(), "@alice@example.social".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert!(normalize("twitter", "@far_too_long_for_twitter").is_err());
+        assert!(normalize("github", "alice_b").is_err());
+        assert!(normalize("email", "alice").is_err());
+        assert!(normalize("phone", "call me").is_err());
+        assert!(normalize("website", "not a site").is_err());
+        assert!(normalize("", "value").is_err());
+        assert!(normalize("twitter", " ").is_err());
+    }
+
+    #[test]
+    fn colliding_keys_are_rejected() {
+        let socials = BTreeMap::from([
+            ("twitter".to_string(), "@alice".to_string()),
+            ("X".to_string(), "@bob".to_string()),
+        ]);
+        assert!(matches!(
+            normalize_all(socials),
+            Err(UpdateError::Duplicate { id, .. }) if id == "twitter"
+        ));
+    }
+}
@@ -1,8 +1,16 @@
-use crate::{request::Update, Contact, ContactBook, PeerStatus};
-use automerge::AutoCommit;
-use kinode_process_lib::{Address, Message, Request};
+use crate::{
+    backups,
+    conflicts::{self, Conflict},
+    contact_book::Task,
+    migrations,
+    request::{RestoreMode, Update},
+    response::UpdateError,
+    storage, Contact, ContactBook, PeerStatus,
+};
+use automerge::{AutoCommit, ChangeHash};
+use kinode_process_lib::{vfs, Address, Message, Request};
 use serde::{ser::SerializeStruct, Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use uuid::Uuid;
 
 #[derive(Debug, Default)]
@@ -11,11 +19,114 @@ pub struct State {
     books: HashMap<Uuid, AutoCommit>,
     /// An invite to become a peer in a new contact book, and who it's from
     pending_invites: HashMap<Uuid, Invite>,
+    /// Contacts other nodes have shared with us, waiting to be imported into a book
+    pending_cards: HashMap<Uuid, ContactCard>,
+    /// Our own metadata about contacts, by book and then contact ID. Unlike
+    /// the books, this is never synced to peers.
+    local: HashMap<Uuid, BTreeMap<String, LocalContactMeta>>,
+    /// Each book's heads when we last marked it as visited, to show what
+    /// changed since.
+    last_visits: HashMap<Uuid, Vec<ChangeHash>>,
     /// Invites we've sent out that haven't been accepted or rejected yet
     outgoing_invites: HashMap<Uuid, (Address, PeerStatus)>,
     /// Book-syncing messages that failed to send. We retry these periodically until
     /// either they succeed or the peer is removed from the book.
     pub failed_messages: HashMap<Address, Message>,
+    /// Tasks we've already set a reminder timer for, and the due date of the
+    /// earliest one. Not persisted: reminders are rescheduled on startup.
+    scheduled_reminders: HashMap<(Uuid, String), u64>,
+    /// Each book's heads when we last looked through it for reminders, so
+    /// that books are only read again once they change. Not persisted.
+    reminder_heads: HashMap<Uuid, Vec<ChangeHash>>,
+    /// Peers we've asked for each blob we're missing, so that each is only
+    /// asked once. Not persisted: we ask again after a restart.
+    blob_requests: HashMap<String, HashSet<Address>>,
+    /// Contact fields with concurrent values in each book, keyed by contact ID.
+    /// Not persisted: recomputed from the documents on startup.
+    conflicts: HashMap<Uuid, BTreeMap<String, Vec<Conflict>>>,
+    /// Set if saved state existed but couldn't be loaded on startup. Not persisted.
+    load_error: Option<String>,
+    /// Our own recent edits to each book that can be undone or redone.
+    /// Not persisted: a restart clears them.
+    edits: HashMap<Uuid, EditHistory>,
+    /// What of each book we last wrote to VFS, so that persisting only
+    /// writes what changed since. Not persisted.
+    saved: HashMap<Uuid, storage::SavedBook>,
+    /// Books removed since we last persisted, whose files are yet to be deleted.
+    removed_books: HashSet<Uuid>,
+    /// Books from before they were stored in VFS, as saved in the state.
+    /// Each is persisted with the state until it is first written to VFS.
+    legacy_books: HashMap<Uuid, Vec<u8>>,
+    /// Books whose files couldn't be loaded on startup. They stay listed
+    /// in the persisted state so that we try them again next time.
+    unreadable_books: HashSet<Uuid>,
+}
+
+/// How many of our edits to a book can be undone.
+const MAX_UNDO: usize = 50;
+
+/// An edit we made to a book, as the book's heads just before and after it.
+#[derive(Debug, Clone)]
+pub struct Edit {
+    pub before: Vec<ChangeHash>,
+    pub after: Vec<ChangeHash>,
+}
+
+#[derive(Debug, Default)]
+struct EditHistory {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+}
+
+fn push_bounded(stack: &mut Vec<Edit>, edit: Edit) {
+    if stack.len() >= MAX_UNDO {
+        stack.remove(0);
+    }
+    stack.push(edit);
+}
+
+/// The version of the persisted state format. Bump this and add a step to
+/// `STATE_MIGRATIONS` whenever the serialized shape of `State` changes.
+const STATE_VERSION: u64 = 3;
+
+/// `STATE_MIGRATIONS[n]` upgrades serialized state from version `n` to `n + 1`.
+const STATE_MIGRATIONS: [fn(serde_json::Value) -> anyhow::Result<serde_json::Value>;
+    STATE_VERSION as usize] = [unversioned_to_v1, add_local_metadata, books_to_vfs];
+
+/// What we actually store with `set_state`.
+#[derive(Serialize, Deserialize)]
+struct StateEnvelope<T> {
+    version: u64,
+    state: T,
+}
+
+/// The state as saved by `persist`. Books are stored in VFS by `storage`,
+/// so only their IDs are listed here.
+#[derive(Deserialize)]
+struct SavedState {
+    books: Vec<Uuid>,
+    pending_invites: HashMap<Uuid, Invite>,
+    #[serde(default)]
+    pending_cards: HashMap<Uuid, ContactCard>,
+    outgoing_invites: HashMap<Uuid, (Address, PeerStatus)>,
+    local: HashMap<Uuid, BTreeMap<String, LocalContactMeta>>,
+    #[serde(default)]
+    last_visits: HashMap<Uuid, Vec<ChangeHash>>,
+    /// Books saved in the state itself, before version 3, that have yet
+    /// to be written to VFS. They are the next time we persist.
+    #[serde(default)]
+    legacy_books: HashMap<Uuid, Vec<u8>>,
+}
+
+/// Context attached to a reminder timer, so we know which task is due when it fires.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Reminder {
+    pub book_id: Uuid,
+    pub task_id: String,
+    /// The due date the timer was set for. Only the timer for the task's
+    /// earliest scheduled date counts, any others are stale.
+    #[serde(default)]
+    pub due: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -26,6 +137,24 @@ pub struct Invite {
     pub data: Vec<u8>,
 }
 
+/// Things about a contact that only matter to us, such as whether we've
+/// starred it. Kept out of the contact book so it isn't forced on every peer.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct LocalContactMeta {
+    pub favorite: bool,
+    /// In seconds since the unix epoch.
+    pub last_viewed: Option<u64>,
+    pub notes: Option<String>,
+}
+
+/// A single contact shared with us by another node.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ContactCard {
+    pub from: Address,
+    pub id: String,
+    pub contact: Contact,
+}
+
 impl State {
     pub fn new(our: &Address) -> Self {
         let mut state = Self::default();
@@ -33,36 +162,249 @@ impl State {
         let mut crdt = AutoCommit::default();
         let mut contact_book = ContactBook::new("My Contacts".to_string(), our);
         contact_book
-            .apply_update(Update::AddContact(
-                "Doria".to_string(),
-                Contact {
-                    description: Some("Developer @ Kinode".to_string()),
-                    socials: BTreeMap::from([(
-                        "twitter".to_string(),
-                        "https://twitter.com/m_e_doria".to_string(),
-                    )]),
-                },
-            ))
+            .apply_update(
+                our,
+                Update::AddContact(
+                    "Doria".to_string(),
+                    Contact {
+                        description: Some("Developer @ Kinode".into()),
+                        socials: BTreeMap::from([(
+                            "twitter".to_string(),
+                            "https://twitter.com/m_e_doria".to_string(),
+                        )]),
+                        ..Default::default()
+                    },
+                ),
+            )
             .unwrap();
         autosurgeon::reconcile(&mut crdt, &contact_book).unwrap();
         state.add_book(book_id, crdt);
         state
     }
+    /// Load saved state, migrating it from older versions if needed. If there
+    /// is saved state that we can't read, it is backed up to VFS before we
+    /// start over, and the error is shown to the user.
+    pub fn load(our: &Address) -> Self {
+        storage::create_drive(our).expect("couldn't create book drive");
+        let mut state = Self::load_or_new(our);
+        let book_ids: Vec<Uuid> = state.books.keys().cloned().collect();
+        for book_id in book_ids {
+            state.refresh_conflicts(&book_id);
+        }
+        state
+    }
+    fn load_or_new(our: &Address) -> Self {
+        let Some(bytes) = kinode_process_lib::get_state() else {
+            println!("generating new state");
+            return Self::new(our);
+        };
+        match Self::from_bytes(&bytes).and_then(|saved| Self::from_saved(our, saved)) {
+            Ok(state) => {
+                println!("loading saved state");
+                state
+            }
+            Err(e) => {
+                println!("failed to load saved state: {:?}", e);
+                // never overwrite state we couldn't read without keeping a copy
+                let path = back_up_unreadable_state(our, &bytes)
+                    .expect("failed to back up unreadable state!");
+                let mut state = Self::new(our);
+                state.load_error = Some(format!(
+                    "saved state could not be loaded ({}), a backup was saved to {}",
+                    e, path
+                ));
+                state
+            }
+        }
+    }
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<SavedState> {
+        let (version, mut state) =
+            match serde_json::from_slice::<StateEnvelope<serde_json::Value>>(bytes) {
+                Ok(envelope) => (envelope.version, envelope.state),
+                // saved before state was versioned
+                Err(_) => (0, serde_json::from_slice(bytes)?),
+            };
+        if version > STATE_VERSION {
+            return Err(anyhow::anyhow!(
+                "state has version {}, newer than our {}",
+                version,
+                STATE_VERSION
+            ));
+        }
+        for migration in &STATE_MIGRATIONS[version as usize..] {
+            state = migration(state)?;
+        }
+        Ok(serde_json::from_value(state)?)
+    }
+    /// Load the books listed in saved state from VFS. A book whose file
+    /// can't be read is left out, and its file left alone, rather than
+    /// failing the whole load. It stays listed, to be tried again next time.
+    fn from_saved(our: &Address, saved: SavedState) -> anyhow::Result<Self> {
+        let mut state = State {
+            pending_invites: saved.pending_invites,
+            pending_cards: saved.pending_cards,
+            outgoing_invites: saved.outgoing_invites,
+            local: saved.local,
+            last_visits: saved.last_visits,
+            ..Default::default()
+        };
+        let mut legacy_books = saved.legacy_books;
+        for book_id in saved.books {
+            let mut book = match legacy_books.remove(&book_id) {
+                // there is no other copy of these, so fail rather than lose them
+                Some(bytes) => {
+                    let book = AutoCommit::load(&bytes)?;
+                    state.legacy_books.insert(book_id, bytes);
+                    book
+                }
+                None => match storage::load(our, &book_id) {
+                    Ok((book, saved)) => {
+                        state.saved.insert(book_id, saved);
+                        book
+                    }
+                    Err(e) => {
+                        println!("failed to load book {}: {:?}", book_id, e);
+                        state.unreadable_books.insert(book_id);
+                        continue;
+                    }
+                },
+            };
+            // e.g. a book from a newer version of the app: keep it, unread
+            if let Err(e) = migrations::migrate(&mut book) {
+                println!("failed to migrate book {}: {:?}", book_id, e);
+                state.unreadable_books.insert(book_id);
+                continue;
+            }
+            state.books.insert(book_id, book);
+        }
+        if !state.unreadable_books.is_empty() {
+            let unreadable: Vec<String> = state
+                .unreadable_books
+                .iter()
+                .map(|book_id| book_id.to_string())
+                .collect();
+            state.load_error = Some(format!(
+                "books {} could not be loaded, their files were kept in the books drive",
+                unreadable.join(", ")
+            ));
+        }
+        Ok(state)
+    }
+    pub fn get_load_error(&self) -> Option<&String> {
+        self.load_error.as_ref()
+    }
     pub fn add_book(&mut self, book_id: Uuid, book: AutoCommit) {
         self.books.insert(book_id, book);
+        // a new document, so it has to be saved in full
+        self.saved.remove(&book_id);
+        self.removed_books.remove(&book_id);
+        self.legacy_books.remove(&book_id);
+        self.unreadable_books.remove(&book_id);
     }
     pub fn remove_book(&mut self, book_id: &Uuid) {
         self.books.remove(book_id);
+        if self.saved.remove(book_id).is_some() {
+            self.removed_books.insert(*book_id);
+        }
+        self.legacy_books.remove(book_id);
+        self.local.remove(book_id);
+        self.conflicts.remove(book_id);
+        self.reminder_heads.remove(book_id);
+        self.edits.remove(book_id);
+        self.last_visits.remove(book_id);
+    }
+    /// Remember the book as it is now, to later see what changed since.
+    pub fn mark_visited(&mut self, book_id: Uuid) -> Option<()> {
+        let heads = self.get_heads(&book_id)?;
+        self.last_visits.insert(book_id, heads);
+        Some(())
+    }
+    pub fn get_last_visit(&self, book_id: &Uuid) -> Option<&Vec<ChangeHash>> {
+        self.last_visits.get(book_id)
+    }
+    /// The book's current heads, to record an `Edit`.
+    pub fn get_heads(&mut self, book_id: &Uuid) -> Option<Vec<ChangeHash>> {
+        Some(self.books.get_mut(book_id)?.get_heads())
+    }
+    /// Record a new edit we made, which can then be undone. Anything
+    /// that was undone before it can no longer be redone.
+    pub fn record_edit(&mut self, book_id: Uuid, edit: Edit) {
+        let history = self.edits.entry(book_id).or_default();
+        history.redo.clear();
+        push_bounded(&mut history.undo, edit);
+    }
+    /// The most recent edit that can be undone.
+    pub fn last_undo(&self, book_id: &Uuid) -> Option<Edit> {
+        self.edits.get(book_id)?.undo.last().cloned()
+    }
+    /// The most recently undone edit.
+    pub fn last_redo(&self, book_id: &Uuid) -> Option<Edit> {
+        self.edits.get(book_id)?.redo.last().cloned()
+    }
+    /// Call once `last_undo` has been undone by the edit `undo`, which
+    /// can now be reverted to redo it.
+    pub fn mark_undone(&mut self, book_id: &Uuid, undo: Edit) {
+        if let Some(history) = self.edits.get_mut(book_id)
+            && history.undo.pop().is_some()
+        {
+            push_bounded(&mut history.redo, undo);
+        }
+    }
+    /// Call once `last_redo` has been redone by the edit `redo`, which
+    /// can now be reverted to undo it again.
+    pub fn mark_redone(&mut self, book_id: &Uuid, redo: Edit) {
+        if let Some(history) = self.edits.get_mut(book_id)
+            && history.redo.pop().is_some()
+        {
+            push_bounded(&mut history.undo, redo);
+        }
+    }
+    /// Look for conflicting values in a book. Call after anything that may
+    /// have introduced or cleared a conflict.
+    pub fn refresh_conflicts(&mut self, book_id: &Uuid) {
+        let Some(crdt) = self.books.get(book_id) else {
+            return;
+        };
+        match conflicts::find(crdt) {
+            Ok(found) => {
+                self.conflicts.insert(*book_id, found);
+            }
+            Err(e) => println!("failed to check book {} for conflicts: {}", book_id, e),
+        }
+    }
+    pub fn get_conflicts(&self) -> &HashMap<Uuid, BTreeMap<String, Vec<Conflict>>> {
+        &self.conflicts
     }
     pub fn get_book_mut(&mut self, book_id: &Uuid) -> Option<&mut AutoCommit> {
         self.books.get_mut(book_id)
     }
+    /// Books that fail to hydrate are left out rather than taking down the process.
     pub fn get_books_hydrated(&self) -> HashMap<Uuid, ContactBook> {
         self.books
             .iter()
-            .map(|(k, v)| (*k, autosurgeon::hydrate(v).unwrap()))
+            .filter_map(|(k, v)| match autosurgeon::hydrate(v) {
+                Ok(book) => Some((*k, book)),
+                Err(e) => {
+                    println!("failed to hydrate book {}: {}", k, e);
+                    None
+                }
+            })
             .collect()
     }
+    pub fn get_local_meta_mut(
+        &mut self,
+        book_id: Uuid,
+        contact_id: String,
+    ) -> &mut LocalContactMeta {
+        self.local
+            .entry(book_id)
+            .or_default()
+            .entry(contact_id)
+            .or_default()
+    }
+    pub fn get_local_meta(&self) -> &HashMap<Uuid, BTreeMap<String, LocalContactMeta>> {
+        &self.local
+    }
     pub fn add_invite(&mut self, book_id: Uuid, invite: Invite) {
         self.pending_invites.insert(book_id, invite);
     }
@@ -72,6 +414,33 @@ impl State {
     pub fn get_invites(&self) -> &HashMap<Uuid, Invite> {
         &self.pending_invites
     }
+    pub fn add_card(&mut self, card: ContactCard) {
+        self.pending_cards.insert(Uuid::new_v4(), card);
+    }
+    /// Note that we're asking `peer` for a blob. False if we already have.
+    pub fn mark_blob_requested(&mut self, hash: &str, peer: &Address) -> bool {
+        self.blob_requests
+            .entry(hash.to_string())
+            .or_default()
+            .insert(peer.clone())
+    }
+    /// Forget who we asked for a blob, now that we have it.
+    pub fn mark_blob_received(&mut self, hash: &str) {
+        self.blob_requests.remove(hash);
+    }
+    /// How many of our pending cards `from` sent us.
+    pub fn count_cards_from(&self, from: &Address) -> usize {
+        self.pending_cards
+            .values()
+            .filter(|card| &card.from == from)
+            .count()
+    }
+    pub fn remove_card(&mut self, card_id: &Uuid) -> Option<ContactCard> {
+        self.pending_cards.remove(card_id)
+    }
+    pub fn get_cards(&self) -> &HashMap<Uuid, ContactCard> {
+        &self.pending_cards
+    }
     pub fn add_outgoing_invite(&mut self, book_id: Uuid, address: Address, status: PeerStatus) {
         self.outgoing_invites.insert(book_id, (address, status));
     }
@@ -92,58 +461,368 @@ impl State {
         }
         Ok(())
     }
-    pub fn persist(&self) {
+    /// Set a timer for every open task assigned to us whose due date is in
+    /// the future, unless it already has one that fires no later. Only books
+    /// that changed since we last looked are read.
+    pub fn schedule_reminders(&mut self, our: &Address) {
+        let now = crate::now();
+        for (book_id, crdt) in self.books.iter_mut() {
+            let heads = crdt.get_heads();
+            if self.reminder_heads.get(book_id) == Some(&heads) {
+                continue;
+            }
+            self.reminder_heads.insert(*book_id, heads);
+            let book: ContactBook = match autosurgeon::hydrate(crdt) {
+                Ok(book) => book,
+                Err(e) => {
+                    println!("failed to hydrate book {}: {}", book_id, e);
+                    continue;
+                }
+            };
+            for (task_id, task) in book.tasks {
+                if task.done || task.due <= now || &task.assignee != our {
+                    continue;
+                }
+                let key = (*book_id, task_id.clone());
+                // a later timer finds the task not yet due and sets a new one
+                if self
+                    .scheduled_reminders
+                    .get(&key)
+                    .is_some_and(|due| *due <= task.due)
+                {
+                    continue;
+                }
+                self.scheduled_reminders.insert(key, task.due);
+                let reminder = Reminder {
+                    book_id: *book_id,
+                    task_id,
+                    due: task.due,
+                };
+                kinode_process_lib::timer::set_timer(
+                    (task.due - now) * 1000,
+                    Some(serde_json::to_vec(&reminder).unwrap()),
+                );
+            }
+        }
+    }
+    /// Called when a reminder timer fires. Returns the task if it is still
+    /// open and actually due, since it may have been edited since we set the timer.
+    pub fn take_due_reminder(&mut self, reminder: &Reminder) -> Option<Task> {
+        let key = (reminder.book_id, reminder.task_id.clone());
+        // superseded by a timer for an earlier date, which has fired or will
+        if self.scheduled_reminders.get(&key) != Some(&reminder.due) {
+            return None;
+        }
+        self.scheduled_reminders.remove(&key);
+        // the task may have moved to a later date, so look at the book again
+        // even if it hasn't changed since, to set a timer for that
+        self.reminder_heads.remove(&reminder.book_id);
+        let crdt = self.books.get(&reminder.book_id)?;
+        let book: ContactBook = autosurgeon::hydrate(crdt).ok()?;
+        let task = book.tasks.get(&reminder.task_id)?;
+        if task.done || task.due > crate::now() {
+            return None;
+        }
+        Some(task.clone())
+    }
+    /// Everything we'd need to start over: all books, invites and local
+    /// metadata, for `backups::save`.
+    pub fn archive(&mut self) -> anyhow::Result<backups::Archive> {
+        let state = serde_json::to_vec(&StateEnvelope {
+            version: STATE_VERSION,
+            state: &*self,
+        })?;
+        let books = self
+            .books
+            .iter_mut()
+            .map(|(book_id, book)| (*book_id, book.save()))
+            .collect();
+        Ok(backups::Archive {
+            created: crate::now(),
+            state,
+            books,
+        })
+    }
+    /// Load an archive made by `archive`. When merging, each archived book
+    /// is merged into ours and we gain any invites or metadata we didn't
+    /// have. When replacing, our books that aren't in the archive are
+    /// dropped. That is only for books no one else has: it fails if any of
+    /// ours is shared with peers, who would merge their copies back in.
+    pub fn restore(
+        &mut self,
+        our: &Address,
+        archive: backups::Archive,
+        mode: &RestoreMode,
+    ) -> anyhow::Result<()> {
+        if let RestoreMode::Replace = mode {
+            let our = our.to_string();
+            for (book_id, book) in self.get_books_hydrated() {
+                if book.peers.keys().any(|peer| peer != &our) {
+                    return Err(UpdateError::invalid(
+                        "restore mode",
+                        format!("book {} is shared with peers, restore by merging", book_id),
+                    )
+                    .into());
+                }
+            }
+        }
+        // read the whole archive before changing anything, so that a bad
+        // one leaves us as we were
+        let saved = Self::from_bytes(&archive.state)?;
+        let mut books = HashMap::new();
+        for (book_id, bytes) in &archive.books {
+            let mut book = AutoCommit::load(bytes)?;
+            migrations::migrate(&mut book)?;
+            books.insert(*book_id, book);
+        }
+
+        if let RestoreMode::Replace = mode {
+            let book_ids: Vec<Uuid> = self.books.keys().cloned().collect();
+            for book_id in book_ids {
+                self.remove_book(&book_id);
+            }
+            self.pending_invites.clear();
+            self.pending_cards.clear();
+            self.outgoing_invites.clear();
+            self.local.clear();
+        }
+        for (book_id, mut book) in books {
+            match self.books.get_mut(&book_id) {
+                Some(ours) => {
+                    ours.merge(&mut book)?;
+                }
+                None => self.add_book(book_id, book),
+            }
+        }
+        for (book_id, invite) in saved.pending_invites {
+            self.pending_invites.entry(book_id).or_insert(invite);
+        }
+        for (card_id, card) in saved.pending_cards {
+            self.pending_cards.entry(card_id).or_insert(card);
+        }
+        for (book_id, invite) in saved.outgoing_invites {
+            self.outgoing_invites.entry(book_id).or_insert(invite);
+        }
+        for (book_id, metas) in saved.local {
+            let ours = self.local.entry(book_id).or_default();
+            for (contact_id, meta) in metas {
+                ours.entry(contact_id).or_insert(meta);
+            }
+        }
+        for (book_id, heads) in saved.last_visits {
+            self.last_visits.entry(book_id).or_insert(heads);
+        }
+
+        let book_ids: Vec<Uuid> = self.books.keys().cloned().collect();
+        for book_id in book_ids {
+            self.refresh_conflicts(&book_id);
+        }
+        Ok(())
+    }
+    /// Write the changes to books since we last persisted to VFS, delete
+    /// the files of removed books, and save everything else with `set_state`.
+    pub fn persist(&mut self, our: &Address) {
+        for book_id in self.removed_books.drain() {
+            if let Err(e) = storage::remove(our, &book_id) {
+                println!("failed to remove book {}: {:?}", book_id, e);
+            }
+        }
+        for (book_id, book) in self.books.iter_mut() {
+            // if saving fails, the book is written in full next time
+            let saved = self.saved.remove(book_id);
+            match storage::save(our, book_id, book, saved) {
+                Ok(saved) => {
+                    self.saved.insert(*book_id, saved);
+                    self.legacy_books.remove(book_id);
+                }
+                Err(e) => {
+                    println!("failed to save book {}: {:?}", book_id, e);
+                    // the state is still its only copy, so keep that current
+                    if let Some(bytes) = self.legacy_books.get_mut(book_id) {
+                        *bytes = book.save();
+                    }
+                }
+            }
+        }
         kinode_process_lib::set_state(
-            &serde_json::to_vec(self).expect("failed to serialize state!"),
+            &serde_json::to_vec(&StateEnvelope {
+                version: STATE_VERSION,
+                state: self,
+            })
+            .expect("failed to serialize state!"),
         );
     }
 }
 
+/// 0 -> 1: state is wrapped in a versioned envelope, and `pending_cards`
+/// (which defaults to empty) was added. Nothing to change in the state itself.
+fn unversioned_to_v1(state: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    Ok(state)
+}
+
+/// 1 -> 2: add our private per-contact metadata, empty to begin with.
+fn add_local_metadata(mut state: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    state
+        .as_object_mut()
+        .ok_or(anyhow::anyhow!("state is not an object"))?
+        .insert("local".to_string(), serde_json::json!({}));
+    Ok(state)
+}
+
+/// 2 -> 3: books are stored in VFS instead of in the state, which only
+/// lists their IDs. Until each is written to VFS, it is kept as one of the
+/// `legacy_books`.
+fn books_to_vfs(mut state: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    let state_object = state
+        .as_object_mut()
+        .ok_or(anyhow::anyhow!("state is not an object"))?;
+    let books = state_object
+        .remove("books")
+        .ok_or(anyhow::anyhow!("state has no books"))?;
+    let book_ids: Vec<serde_json::Value> = books
+        .as_object()
+        .ok_or(anyhow::anyhow!("books is not an object"))?
+        .keys()
+        .map(|book_id| serde_json::Value::String(book_id.clone()))
+        .collect();
+    state_object.insert("books".to_string(), book_ids.into());
+    state_object.insert("legacy_books".to_string(), books);
+    Ok(state)
+}
+
+/// Write state we failed to parse to the VFS, returning the path it was saved at.
+fn back_up_unreadable_state(our: &Address, bytes: &[u8]) -> anyhow::Result<String> {
+    let drive = vfs::create_drive(our.package_id(), "state_backups", None)?;
+    let path = format!("{}/unreadable-state-{}.json", drive, crate::now());
+    vfs::create_file(&path, None)?.write(bytes)?;
+    Ok(path)
+}
+
+/// Serializes to the shape of `SavedState`, without the books themselves.
 impl Serialize for State {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let mut ser = serializer.serialize_struct("State", 3)?;
-        let books_as_bytes: HashMap<Uuid, Vec<u8>> = self
+        let mut ser = serializer.serialize_struct("State", 7)?;
+        let book_ids: Vec<&Uuid> = self
             .books
-            .iter()
-            .map(|(k, v)| (*k, v.clone().save()))
+            .keys()
+            .chain(self.unreadable_books.iter())
             .collect();
-        ser.serialize_field("books", &books_as_bytes)?;
+        ser.serialize_field("books", &book_ids)?;
+        ser.serialize_field("legacy_books", &self.legacy_books)?;
         ser.serialize_field("pending_invites", &self.pending_invites)?;
+        ser.serialize_field("pending_cards", &self.pending_cards)?;
         ser.serialize_field("outgoing_invites", &self.outgoing_invites)?;
+        ser.serialize_field("local", &self.local)?;
+        ser.serialize_field("last_visits", &self.last_visits)?;
         ser.end()
     }
 }
 
-impl<'de> Deserialize<'de> for State {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        #[derive(Deserialize)]
-        struct StateHelper {
-            books: HashMap<Uuid, Vec<u8>>,
-            pending_invites: HashMap<Uuid, Invite>,
-            outgoing_invites: HashMap<Uuid, (Address, PeerStatus)>,
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{address, our};
+    use autosurgeon::reconcile;
 
-        let helper = StateHelper::deserialize(deserializer)?;
-        let books: Result<HashMap<Uuid, AutoCommit>, _> = helper
-            .books
-            .into_iter()
-            .map(|(k, v)| match AutoCommit::load(&v) {
-                Ok(auto_commit) => Ok((k, auto_commit)),
-                Err(e) => Err(e),
-            })
-            .collect();
-        let books = books.map_err(serde::de::Error::custom)?;
-        Ok(State {
-            books,
-            pending_invites: helper.pending_invites,
-            outgoing_invites: helper.outgoing_invites,
-            failed_messages: HashMap::new(),
+    /// State as saved before it was versioned, with a single book in it.
+    fn unversioned_state() -> (Uuid, Vec<u8>, Vec<u8>) {
+        let mut book = AutoCommit::new();
+        reconcile(&mut book, &ContactBook::new("Test".to_string(), &our())).unwrap();
+        unversioned_state_with(book)
+    }
+
+    /// State as saved before it was versioned, with `book` in it.
+    fn unversioned_state_with(mut book: AutoCommit) -> (Uuid, Vec<u8>, Vec<u8>) {
+        let book_id = Uuid::new_v4();
+        let bytes = book.save();
+        let state = serde_json::json!({
+            "books": { (book_id.to_string()): bytes },
+            "pending_invites": {},
+            "outgoing_invites": {},
+        });
+        (book_id, bytes, serde_json::to_vec(&state).unwrap())
+    }
+
+    #[test]
+    fn unversioned_state_is_migrated() {
+        let (book_id, bytes, state) = unversioned_state();
+        let saved = State::from_bytes(&state).unwrap();
+        assert_eq!(saved.books, vec![book_id]);
+        assert_eq!(saved.legacy_books[&book_id], bytes);
+        assert!(saved.local.is_empty());
+        assert!(saved.pending_cards.is_empty());
+    }
+
+    #[test]
+    fn newer_state_is_refused() {
+        let state = serde_json::json!({ "version": STATE_VERSION + 1, "state": {} });
+        assert!(State::from_bytes(&serde_json::to_vec(&state).unwrap()).is_err());
+    }
+
+    #[test]
+    fn legacy_books_are_persisted_until_written_to_vfs() {
+        let (book_id, bytes, state) = unversioned_state();
+        let state = State::from_saved(&our(), State::from_bytes(&state).unwrap()).unwrap();
+        assert!(state.books.contains_key(&book_id));
+
+        let persisted = serde_json::to_vec(&StateEnvelope {
+            version: STATE_VERSION,
+            state: &state,
         })
+        .unwrap();
+        let saved = State::from_bytes(&persisted).unwrap();
+        assert_eq!(saved.books, vec![book_id]);
+        assert_eq!(saved.legacy_books[&book_id], bytes);
+    }
+
+    #[test]
+    fn books_that_fail_to_migrate_are_kept_unread() {
+        let mut book = AutoCommit::new();
+        reconcile(&mut book, &ContactBook::new("Test".to_string(), &our())).unwrap();
+        autosurgeon::reconcile_prop(
+            &mut book,
+            automerge::ROOT,
+            "schema_version",
+            migrations::SCHEMA_VERSION + 1,
+        )
+        .unwrap();
+        let (book_id, bytes, state) = unversioned_state_with(book);
+        let state = State::from_saved(&our(), State::from_bytes(&state).unwrap()).unwrap();
+        assert!(state.books.is_empty());
+        assert!(state.get_load_error().is_some());
+
+        let persisted = serde_json::to_vec(&StateEnvelope {
+            version: STATE_VERSION,
+            state: &state,
+        })
+        .unwrap();
+        let saved = State::from_bytes(&persisted).unwrap();
+        assert_eq!(saved.books, vec![book_id]);
+        assert_eq!(saved.legacy_books[&book_id], bytes);
+    }
+
+    #[test]
+    fn shared_books_are_never_replaced() {
+        let book_id = Uuid::new_v4();
+        let mut book = ContactBook::new("Test".to_string(), &our());
+        book.peers
+            .insert(address("teammate.os").to_string(), PeerStatus::ReadWrite);
+        let mut doc = AutoCommit::new();
+        reconcile(&mut doc, &book).unwrap();
+        let mut state = State::default();
+        state.add_book(book_id, doc);
+
+        let archive = backups::Archive {
+            created: 0,
+            state: vec![],
+            books: HashMap::new(),
+        };
+        assert!(state
+            .restore(&our(), archive, &RestoreMode::Replace)
+            .is_err());
+        assert!(state.books.contains_key(&book_id));
     }
 }
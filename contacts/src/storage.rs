@@ -0,0 +1,103 @@
+//! Each book's automerge document, stored as its own files in the package VFS
+//! drive: a snapshot of the whole book, plus a log of the changes made since,
+//! which `load_incremental` replays. Once the log grows past `COMPACT_AFTER`
+//! bytes, the book is written as a new snapshot and the log is started over.
+//! `set_state` holds just a small index of everything else.
+use automerge::{AutoCommit, ChangeHash};
+use kinode_process_lib::{vfs, Address};
+use uuid::Uuid;
+
+const DRIVE: &str = "books";
+
+/// Bytes of changes a book's log may hold before the book is compacted.
+const COMPACT_AFTER: usize = 512 * 1024;
+
+/// What of a book is on disk, as of when we last loaded or saved it.
+#[derive(Debug)]
+pub struct SavedBook {
+    heads: Vec<ChangeHash>,
+    log_len: usize,
+}
+
+pub fn create_drive(our: &Address) -> anyhow::Result<()> {
+    vfs::create_drive(our.package_id(), DRIVE, None)?;
+    Ok(())
+}
+
+fn snapshot_path(our: &Address, book_id: &Uuid) -> String {
+    format!("/{}/{}/{}.automerge", our.package_id(), DRIVE, book_id)
+}
+
+fn log_path(our: &Address, book_id: &Uuid) -> String {
+    format!("/{}/{}/{}.changes", our.package_id(), DRIVE, book_id)
+}
+
+/// Load a book's snapshot and replay its log on top.
+pub fn load(our: &Address, book_id: &Uuid) -> anyhow::Result<(AutoCommit, SavedBook)> {
+    let bytes = vfs::open_file(&snapshot_path(our, book_id), false, None)?.read()?;
+    let mut book = AutoCommit::load(&bytes)?;
+    // books saved before we kept a log don't have one
+    let log = match vfs::open_file(&log_path(our, book_id), false, None) {
+        Ok(file) => file.read()?,
+        Err(_) => vec![],
+    };
+    book.load_incremental(&log)?;
+    let saved = SavedBook {
+        heads: book.get_heads(),
+        log_len: log.len(),
+    };
+    Ok((book, saved))
+}
+
+/// Write what changed in the book since `saved`, or a new snapshot if it
+/// was never saved or its log is due for compaction.
+pub fn save(
+    our: &Address,
+    book_id: &Uuid,
+    book: &mut AutoCommit,
+    saved: Option<SavedBook>,
+) -> anyhow::Result<SavedBook> {
+    let Some(saved) = saved else {
+        return save_snapshot(our, book_id, book);
+    };
+    let heads = book.get_heads();
+    if heads == saved.heads {
+        return Ok(saved);
+    }
+    // rather than `save_incremental`, which saves what changed since the last
+    // `save`, and we also `save` books to send them to peers
+    let changes: Vec<u8> = book
+        .get_changes(&saved.heads)
+        .iter()
+        .flat_map(|change| change.raw_bytes())
+        .copied()
+        .collect();
+    let log_len = saved.log_len + changes.len();
+    if log_len > COMPACT_AFTER {
+        return save_snapshot(our, book_id, book);
+    }
+    vfs::open_file(&log_path(our, book_id), true, None)?.append(&changes)?;
+    Ok(SavedBook { heads, log_len })
+}
+
+fn save_snapshot(
+    our: &Address,
+    book_id: &Uuid,
+    book: &mut AutoCommit,
+) -> anyhow::Result<SavedBook> {
+    vfs::create_file(&snapshot_path(our, book_id), None)?.write(&book.save())?;
+    // the snapshot has everything in the log, so start an empty one. If we
+    // stop before this, replaying changes already in the snapshot is harmless.
+    vfs::create_file(&log_path(our, book_id), None)?;
+    Ok(SavedBook {
+        heads: book.get_heads(),
+        log_len: 0,
+    })
+}
+
+pub fn remove(our: &Address, book_id: &Uuid) -> anyhow::Result<()> {
+    vfs::remove_file(&snapshot_path(our, book_id), None)?;
+    // books saved before we kept a log don't have one
+    vfs::remove_file(&log_path(our, book_id), None).ok();
+    Ok(())
+}
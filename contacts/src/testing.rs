@@ -0,0 +1,14 @@
+//! Fixtures shared by the unit tests.
+use kinode_process_lib::Address;
+
+/// `node`'s address for this process.
+pub fn address(node: &str) -> Address {
+    format!("{}@contacts:crdt-crm:mothu-et-doria.os", node)
+        .parse()
+        .unwrap()
+}
+
+/// The node the tests run as.
+pub fn our() -> Address {
+    address("our.os")
+}
@@ -13,14 +13,35 @@ function init() {
         .then(response => response.json())
         .then(data => {
             console.log(data);
+            showLoadError(data.load_error);
             updateContactsAndPeers(data);
             populateContactBookSelector(data.books);
             populateInvites(data.pending_invites);
+            populateContactCards(data.pending_cards, data.books);
             enableBookCreation();
+            enableBackups();
             displaySelectedBook();
         });
 }
 
+// Shown if our saved state couldn't be loaded on startup
+function showLoadError(error) {
+    document.getElementById('load-error').innerText = error || '';
+}
+
+// Tell the user why the backend rejected a request
+function showUpdateError(response) {
+    if (response.ok) {
+        return;
+    }
+    response.json()
+        .then(error => {
+            const [kind, details] = Object.entries(error)[0];
+            alert(`${kind}: ${JSON.stringify(details)}`);
+        })
+        .catch(() => alert(`Request failed with status ${response.status}`));
+}
+
 // Populate invites
 function populateInvites(invites) {
     // if invites is null or empty, return
@@ -28,14 +49,84 @@ function populateInvites(invites) {
         document.getElementById('invites-container').innerHTML = '';
         return;
     }
+    // invites come from any node, so nothing in them is trusted as HTML
     const invitesHtml = Object.entries(invites).map(([uuid, invite]) => {
-        return `<form class="invite" onsubmit="acceptInvite('${uuid}', '${invite.from}', '${invite.name}'); return false;">
-                <h2>From: ${invite.from.split('@')[0]}</h2>
-                <p>Book name: ${invite.name}</p>
+        return `<form class="invite" data-uuid="${escapeHtml(uuid)}">
+                <h2>From: ${escapeHtml(invite.from.split('@')[0])}</h2>
+                <p>Book name: ${escapeHtml(invite.name)}</p>
                 <button type="submit">Accept Invite</button>
             </form>`;
     }).join('');
-    document.getElementById('invites-container').innerHTML = '<h1>Invites</h1>' + invitesHtml;
+    const container = document.getElementById('invites-container');
+    container.innerHTML = '<h1>Invites</h1>' + invitesHtml;
+    container.querySelectorAll('.invite').forEach(form => {
+        const invite = invites[form.dataset.uuid];
+        form.addEventListener('submit', e => {
+            e.preventDefault();
+            acceptInvite(form.dataset.uuid, invite.from, invite.name);
+        });
+    });
+}
+
+// Populate contact cards shared with us, each with a selector for the book to import it into
+// Cards come from any node, so they're built from text nodes, never parsed as HTML
+function populateContactCards(cards, books) {
+    const container = document.getElementById('cards-container');
+    container.innerHTML = '';
+    if (!cards || Object.keys(cards).length === 0) {
+        return;
+    }
+    const heading = document.createElement('h1');
+    heading.textContent = 'Shared Contacts';
+    container.appendChild(heading);
+    Object.entries(cards).forEach(([uuid, card]) => {
+        const form = document.createElement('form');
+        form.className = 'invite';
+        form.innerHTML = `
+                <h2 class="cardName"></h2>
+                <p class="cardFrom"></p>
+                <p class="cardDescription"></p>
+                <select name="book"></select>
+                <button type="submit">Import</button>
+                <button type="button" class="dismissCardBtn">Dismiss</button>`;
+        form.querySelector('.cardName').textContent = card.id;
+        form.querySelector('.cardFrom').textContent = `From: ${card.from.split('@')[0]}`;
+        form.querySelector('.cardDescription').textContent = card.contact.description || '';
+        const select = form.querySelector('select');
+        Object.entries(books).forEach(([bookId, book]) => {
+            select.add(new Option(bookFullName(book.name, book.owner), bookId));
+        });
+        form.addEventListener('submit', e => {
+            e.preventDefault();
+            importContactCard(uuid, select.value);
+        });
+        form.querySelector('.dismissCardBtn').addEventListener('click', () => dismissContactCard(uuid));
+        container.appendChild(form);
+    });
+}
+
+function importContactCard(uuid, bookId) {
+    fetch(APP_POST_PATH, {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json',
+        },
+        body: JSON.stringify({
+            "ImportContactCard": [uuid, bookId]
+        }),
+    });
+}
+
+function dismissContactCard(uuid) {
+    fetch(APP_POST_PATH, {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json',
+        },
+        body: JSON.stringify({
+            "DismissContactCard": uuid
+        }),
+    });
 }
 
 function acceptInvite(uuid, from, name) {
@@ -52,7 +143,7 @@ function acceptInvite(uuid, from, name) {
             // set the new book as the selected book using the 'from' and 'name' values
             let books = document.getElementById('contact-book-select').children;
             let newBookElement = Array.from(books).find(
-                book => book.textContent === bookFullName(name, from)
+                book => book.dataset.fullName === bookFullName(name, from)
             );
             if (newBookElement) {
                 document.getElementById('contact-book-select').value = newBookElement.value;
@@ -76,7 +167,12 @@ function populateContactBookSelector(books) {
     const currentBookId = document.getElementById('contact-book-select').value;
     selector.innerHTML = ''; // Clear existing options
     for (const [uuid, book] of Object.entries(books)) {
-        const option = new Option(bookFullName(book.name, book.owner), uuid);
+        const fullName = bookFullName(book.name, book.owner);
+        const option = new Option(book.icon ? `${book.icon} ${fullName}` : fullName, uuid);
+        option.dataset.fullName = fullName;
+        if (bookColor(book)) {
+            option.style.color = bookColor(book);
+        }
         selector.add(option);
     }
     if (currentBookId) {
@@ -121,7 +217,7 @@ function enableBookCreation() {
                 // but we don't have uuid, so need to search for it (ewww)
                 let books = document.getElementById('contact-book-select').children;
                 let newBookElement = Array.from(books).find(
-                    book => book.textContent ===
+                    book => book.dataset.fullName ===
                         bookFullName(newBookName, document.getElementById('our').innerText)
                 );
                 if (newBookElement) {
@@ -135,6 +231,53 @@ function enableBookCreation() {
     });
 }
 
+function enableBackups() {
+    document.getElementById('backupNowBtn').addEventListener('click', function () {
+        fetch(APP_POST_PATH, {
+            method: 'POST',
+            headers: {
+                'Content-Type': 'application/json',
+            },
+            body: JSON.stringify("Backup"),
+        }).then(response => {
+            showUpdateError(response);
+            populateBackups();
+        });
+    });
+    populateBackups();
+}
+
+// Newest first, each of which can be merged into our books or replace them
+function populateBackups() {
+    fetch(`/contacts:crdt-crm:mothu-et-doria.os/backups`)
+        .then(response => response.json())
+        .then(backups => {
+            const list = document.getElementById('backup-list');
+            list.innerHTML = backups.reverse().map(backup => `
+                <li>
+                    ${new Date(backup.created * 1000).toLocaleString()}
+                    <button class="restoreBackupBtn" data-name="${backup.name}" data-mode="Merge">Merge In</button>
+                    <button class="restoreBackupBtn" data-name="${backup.name}" data-mode="Replace">Restore</button>
+                </li>
+            `).join('');
+            list.querySelectorAll('.restoreBackupBtn').forEach(button => {
+                button.addEventListener('click', function () {
+                    const { name, mode } = this.dataset;
+                    if (mode === 'Replace' && !confirm('Replace all your books with this backup? This fails if any of them are shared.')) {
+                        return;
+                    }
+                    fetch(APP_POST_PATH, {
+                        method: 'POST',
+                        headers: {
+                            'Content-Type': 'application/json',
+                        },
+                        body: JSON.stringify({ "RestoreBackup": [name, mode] }),
+                    }).then(showUpdateError);
+                });
+            });
+        });
+}
+
 function updateContactsAndPeers(data) {
     document.getElementById("books").innerHTML = ''; // Clear existing books
     for (const [uuid, book] of Object.entries(data.books)) {
@@ -143,7 +286,12 @@ function updateContactsAndPeers(data) {
         contactBookContent.id = `book-${uuid}`;
         contactBookContent.style.display = 'none';
         contactBookContent.innerHTML = `
-                <h1>Book: ${book.name}</h1>
+                <h1 style="${bookColor(book) ? `color: ${bookColor(book)}` : ''}">Book: ${escapeHtml(book.icon || '')} ${escapeHtml(book.name)}</h1>
+                <p>${escapeHtml(book.description || '')}</p>
+                <button type="button" class="undoBtn">Undo</button>
+                <button type="button" class="redoBtn">Redo</button>
+                <button type="button" class="sinceLastVisitBtn">What's Changed Since My Last Visit</button>
+                <div class="sinceLastVisit"></div>
                 <div id="contacts">
                 </div>
 
@@ -151,6 +299,14 @@ function updateContactsAndPeers(data) {
                 <div id="peers">
                 </div>
 
+                <div id="settings">
+                </div>
+
+                <h1>History</h1>
+                <ul class="history"></ul>
+                <button type="button" class="loadHistoryBtn">Load History</button>
+                <div class="pastVersion"></div>
+
                 <br>
                 <br>
                 <button type="button" class="deleteContactBookBtn">Delete Contact Book</button>
@@ -175,34 +331,50 @@ function updateContactsAndPeers(data) {
         enableAddContact(container, uuid);
 
         // HTTP POST request to /post path for inviting a peer
-        enableInvitePeer(container, uuid);
+        enableInvitePeer(container, uuid, book);
+
+        // Only the owner can change the book's settings
+        populateSettings(container, book, uuid);
+
+        // Page through the book's change history, newest first
+        enableHistory(container, uuid);
+
+        // Undo and redo our own recent changes to the book
+        enableUndoRedo(container, uuid);
+
+        // Show what peers changed since we last looked
+        enableSinceLastVisit(container, uuid);
 
         // Make description fields editable on click and save on enter
         enableEditDescription(container, uuid);
 
         // Add event listeners for socials edit and remove buttons
         enableEditSocials(container, uuid);
+
+        // Favorites are our own and aren't shared with the book's peers
+        enableFavorites(container, uuid);
     }
 }
 
 function populateContacts(container, book, uuid) {
     const contactsHtml = Object.entries(book.contacts).map(([id, contact]) => {
+        const favorite = contact.local && contact.local.favorite;
         return `<div class="contact">
-                <h2>${id}</h2>
-                <p class="editableDescription" contenteditable="false" data-contact-id="${id}">${contact.description || '(no description, click to add)'}</p>
+                <h2>${escapeHtml(id)} <button type="button" class="favoriteBtn" data-contact-id="${escapeHtml(id)}" data-favorite="${favorite ? 'true' : 'false'}">${favorite ? '★' : '☆'}</button></h2>
+                <p class="editableDescription" contenteditable="false" data-contact-id="${escapeHtml(id)}" data-description="${escapeHtml(contact.description || '')}">${escapeHtml(contact.description || '(no description, click to add)')}</p>
                 <div class="socials">${Object.entries(contact.socials).map(([key, value]) => `
                     <span class="socialEntry">
-                        <span>${key}:</span>
-                        <span class="editableSocialValue" contenteditable="true" data-contact-id="${id}" data-social-key="${key}">${value}</span>
-                        <button type="button" class="removeSocialBtn" data-contact-id="${id}" data-social-key="${key}">Remove</button>
+                        <span>${escapeHtml(key)}:</span>
+                        <span class="editableSocialValue" contenteditable="true" data-contact-id="${escapeHtml(id)}" data-social-key="${escapeHtml(key)}">${escapeHtml(value)}</span>
+                        <button type="button" class="removeSocialBtn" data-contact-id="${escapeHtml(id)}" data-social-key="${escapeHtml(key)}">Remove</button>
                     </span>
                 `).join('<br>')}</div>
-                <div class="addSocialForm" data-contact-id="${id}">
-                    <input type="text" placeholder="Social Media Name" class="socialKeyInput">
+                <div class="addSocialForm" data-contact-id="${escapeHtml(id)}">
+                    <input type="text" placeholder="Social Media Name" class="socialKeyInput" list="known-socials">
                     <input type="text" placeholder="Social Media Handle" class="socialValueInput">
                     <button type="button" class="submitSocialBtn">Add Social</button>
                 </div>
-                <button type="button" class="deleteContactBtn" data-contact-id="${id}">Delete</button>
+                <button type="button" class="deleteContactBtn" data-contact-id="${escapeHtml(id)}">Delete</button>
             </div>`;
     }).join('');
     container.querySelector('#contacts').innerHTML =
@@ -224,8 +396,8 @@ function populateContacts(container, book, uuid) {
 function populatePeers(container, book, uuid) {
     const peersHtml = Object.entries(book.peers).map(([address, status]) => {
         return `<div class="peer">
-                <h2>${address.split('@')[0]}</h2>
-                <p>Status: ${status}</p>
+                <h2>${escapeHtml(address.split('@')[0])}</h2>
+                <p>Status: ${escapeHtml(status)}</p>
             </div>`;
     }).join('');
     container.querySelector('#peers').innerHTML =
@@ -257,6 +429,27 @@ function enableDeleteContact(container, uuid) {
     });
 }
 
+function enableFavorites(container, uuid) {
+    container.querySelectorAll('.favoriteBtn').forEach(button => {
+        button.addEventListener('click', function () {
+            const contactId = this.getAttribute('data-contact-id');
+            const favorite = this.getAttribute('data-favorite') !== 'true';
+            fetch(APP_POST_PATH, {
+                method: 'POST',
+                headers: {
+                    'Content-Type': 'application/json',
+                },
+                body: JSON.stringify({
+                    "UpdateLocal": [
+                        uuid,
+                        contactId,
+                        { "SetFavorite": favorite }]
+                }),
+            });
+        });
+    });
+}
+
 function enableDeleteContactBook(container, uuid) {
     container.querySelectorAll('.deleteContactBookBtn').forEach(button => {
         button.addEventListener('click', function () {
@@ -315,13 +508,209 @@ function enableAddContact(container, uuid) {
                 if (response.ok) {
                     e.target.reset(); // Clear the form values upon successful submit
                     container.querySelector(`#customFieldsContainer-${uuid}`).innerHTML = '';
+                } else {
+                    showUpdateError(response);
                 }
             });
         });
     });
 }
 
-function enableInvitePeer(container, uuid) {
+function enableHistory(container, uuid) {
+    const list = container.querySelector('.history');
+    const button = container.querySelector('.loadHistoryBtn');
+    button.addEventListener('click', () => {
+        const offset = list.children.length;
+        fetch(`/contacts:crdt-crm:mothu-et-doria.os/books/${uuid}/history?offset=${offset}&limit=20`)
+            .then(response => response.json())
+            .then(page => {
+                page.changes.forEach(change => {
+                    const item = document.createElement('li');
+                    const when = new Date(change.timestamp * 1000).toLocaleString();
+                    item.innerText = `${when}: ${change.author || 'someone'} ${change.summary || 'made changes'} `;
+                    const view = document.createElement('button');
+                    view.type = 'button';
+                    view.innerText = 'View';
+                    view.addEventListener('click', () => showPastVersion(container, uuid, change));
+                    item.appendChild(view);
+                    list.appendChild(item);
+                });
+                button.innerText = 'Load More';
+                button.hidden = list.children.length >= page.total;
+            });
+    });
+}
+
+function enableUndoRedo(container, uuid) {
+    [['.undoBtn', 'Undo'], ['.redoBtn', 'Redo']].forEach(([selector, request]) => {
+        container.querySelector(selector).addEventListener('click', () => {
+            fetch(APP_POST_PATH, {
+                method: 'POST',
+                headers: {
+                    'Content-Type': 'application/json',
+                },
+                body: JSON.stringify({ [request]: uuid }),
+            }).then(showUpdateError);
+        });
+    });
+}
+
+function enableSinceLastVisit(container, uuid) {
+    container.querySelector('.sinceLastVisitBtn').addEventListener('click', () => {
+        const output = container.querySelector('.sinceLastVisit');
+        fetch(`/contacts:crdt-crm:mothu-et-doria.os/books/${uuid}/diff`)
+            .then(response => {
+                if (response.status === 400) {
+                    // never visited before: everything is new
+                    output.innerText = 'This is your first visit.';
+                    return null;
+                }
+                if (!response.ok) {
+                    showUpdateError(response);
+                    return undefined;
+                }
+                return response.json();
+            })
+            .then(diff => {
+                if (diff === undefined) {
+                    return;
+                }
+                if (diff) {
+                    output.replaceChildren(describeDiff(diff));
+                }
+                fetch(APP_POST_PATH, {
+                    method: 'POST',
+                    headers: {
+                        'Content-Type': 'application/json',
+                    },
+                    body: JSON.stringify({ "MarkVisited": uuid }),
+                });
+            });
+    });
+}
+
+// A list of what changed, built from text so that nothing peers wrote is
+// read as HTML
+function describeDiff(diff) {
+    const lines = [];
+    diff.settings.forEach(change => lines.push(`Book ${change.field} changed`));
+    diff.contacts.added.forEach(id => lines.push(`Added ${id}`));
+    diff.contacts.removed.forEach(id => lines.push(`Removed ${id}`));
+    Object.entries(diff.contacts.changed).forEach(([id, fields]) => fields.forEach(change =>
+        lines.push(`${id}: ${change.field} changed from ${JSON.stringify(change.old)} to ${JSON.stringify(change.new)}`)
+    ));
+    ['organizations', 'deals', 'tasks', 'relationships'].forEach(collection => {
+        const changes = diff[collection];
+        const count = changes.added.length + changes.removed.length + Object.keys(changes.changed).length;
+        if (count > 0) {
+            lines.push(`${count} ${collection} changed`);
+        }
+    });
+    Object.keys(diff.peers_added).forEach(peer => lines.push(`${peer} joined`));
+    Object.keys(diff.peers_removed).forEach(peer => lines.push(`${peer} left`));
+    Object.keys(diff.peers_changed).forEach(peer => lines.push(`${peer}'s access changed`));
+    if (lines.length === 0) {
+        const nothing = document.createElement('p');
+        nothing.textContent = 'Nothing has changed.';
+        return nothing;
+    }
+    const list = document.createElement('ul');
+    lines.forEach(line => {
+        const item = document.createElement('li');
+        item.textContent = line;
+        list.appendChild(item);
+    });
+    return list;
+}
+
+// The book's contacts as of a change, each of which can be restored
+function showPastVersion(container, uuid, change) {
+    fetch(`/contacts:crdt-crm:mothu-et-doria.os/books/${uuid}/at?heads=${change.hash}`)
+        .then(response => response.json())
+        .then(book => {
+            const past = container.querySelector('.pastVersion');
+            const when = new Date(change.timestamp * 1000).toLocaleString();
+            past.innerHTML = `<h2>As of ${when}</h2>` + Object.entries(book.contacts).map(([id, contact]) => `
+                <div class="contact">
+                    <h3>${escapeHtml(id)}</h3>
+                    <p>${escapeHtml(contact.description || '')}</p>
+                    <button type="button" class="restoreVersionBtn" data-contact-id="${escapeHtml(id)}">Restore This Version</button>
+                </div>
+            `).join('');
+            past.querySelectorAll('.restoreVersionBtn').forEach(button => {
+                button.addEventListener('click', () => {
+                    fetch(APP_POST_PATH, {
+                        method: 'POST',
+                        headers: {
+                            'Content-Type': 'application/json',
+                        },
+                        body: JSON.stringify({
+                            "Update": [
+                                uuid,
+                                { "RestoreContactVersion": [button.dataset.contactId, [change.hash]] }
+                            ]
+                        }),
+                    }).then(showUpdateError);
+                });
+            });
+        });
+}
+
+function populateSettings(container, book, uuid) {
+    const our = document.getElementById('our').innerText;
+    if (book.owner !== our) {
+        return;
+    }
+    container.querySelector('#settings').innerHTML = `
+        <h1>Settings</h1>
+        <form class="bookSettingsForm">
+            <label for="settings-name-${uuid}">Name:</label>
+            <input type="text" id="settings-name-${uuid}" name="name" value="${escapeHtml(book.name)}" required>
+            <br>
+            <label for="settings-desc-${uuid}">Description:</label>
+            <input type="text" id="settings-desc-${uuid}" name="description" value="${escapeHtml(book.description || '')}">
+            <br>
+            <label for="settings-color-${uuid}">Color:</label>
+            <input type="color" id="settings-color-${uuid}" name="color" value="${bookColor(book) || '#000000'}">
+            <br>
+            <label for="settings-icon-${uuid}">Icon:</label>
+            <input type="text" id="settings-icon-${uuid}" name="icon" value="${escapeHtml(book.icon || '')}">
+            <br>
+            <label for="settings-status-${uuid}">New peers can:</label>
+            <select id="settings-status-${uuid}" name="default_peer_status">
+                <option value="ReadWrite" ${book.default_peer_status === 'ReadWrite' ? 'selected' : ''}>Edit</option>
+                <option value="ReadOnly" ${book.default_peer_status === 'ReadOnly' ? 'selected' : ''}>View</option>
+            </select>
+            <br>
+            <button type="submit">Save Settings</button>
+        </form>`;
+    container.querySelector('.bookSettingsForm').addEventListener('submit', (e) => {
+        e.preventDefault();
+        const formData = new FormData(e.target);
+        fetch(APP_POST_PATH, {
+            method: 'POST',
+            headers: {
+                'Content-Type': 'application/json',
+            },
+            body: JSON.stringify({
+                "Update": [
+                    uuid,
+                    {
+                        "SetBookSettings": {
+                            "name": formData.get('name'),
+                            "description": formData.get('description') || null,
+                            "color": formData.get('color'),
+                            "icon": formData.get('icon') || null,
+                            "default_peer_status": formData.get('default_peer_status'),
+                        }
+                    }
+                ]
+            }),
+        }).then(showUpdateError);
+    });
+}
+
+function enableInvitePeer(container, uuid, book) {
     container.querySelectorAll('.invitePeerForm').forEach(form => {
         form.addEventListener('submit', (e) => {
             e.preventDefault();
@@ -343,7 +732,7 @@ function enableInvitePeer(container, uuid) {
                     "CreateInvite": [
                         uuid,
                         peer,
-                        "ReadWrite"
+                        book.default_peer_status || "ReadWrite"
                     ]
                 }),
             }).then(response => {
@@ -365,8 +754,13 @@ function enableEditDescription(container, uuid) {
             if (e.key === 'Enter') {
                 e.preventDefault();
                 this.contentEditable = false;
+                const oldDescription = this.getAttribute('data-description');
                 const newDescription = this.innerText;
                 const contactId = this.getAttribute('data-contact-id');
+                const [pos, del, insert] = descriptionSplice(oldDescription, newDescription);
+                if (del === 0 && insert === '') {
+                    return;
+                }
                 fetch(APP_POST_PATH, {
                     method: 'POST',
                     headers: {
@@ -375,7 +769,7 @@ function enableEditDescription(container, uuid) {
                     body: JSON.stringify({
                         "Update": [
                             uuid,
-                            { "EditContactDescription": [contactId, newDescription] }]
+                            { "SpliceContactDescription": [contactId, pos, del, insert] }]
                     }),
                 });
             }
@@ -383,6 +777,44 @@ function enableEditDescription(container, uuid) {
     });
 }
 
+// Turn an edit of a description into a single splice (position, number of
+// characters deleted, text inserted) so that concurrent edits from other
+// peers merge instead of being overwritten. Positions count unicode characters.
+function descriptionSplice(oldText, newText) {
+    const before = Array.from(oldText);
+    const after = Array.from(newText);
+    let prefix = 0;
+    while (prefix < before.length && prefix < after.length && before[prefix] === after[prefix]) {
+        prefix++;
+    }
+    let suffix = 0;
+    while (suffix < before.length - prefix && suffix < after.length - prefix
+        && before[before.length - 1 - suffix] === after[after.length - 1 - suffix]) {
+        suffix++;
+    }
+    return [
+        prefix,
+        before.length - prefix - suffix,
+        after.slice(prefix, after.length - suffix).join(''),
+    ];
+}
+
+// The book's color if it is a valid #rrggbb color, else ''. Peers that can
+// edit a book can write anything there, so don't trust it as CSS.
+function bookColor(book) {
+    return /^#[0-9a-fA-F]{6}$/.test(book.color || '') ? book.color : '';
+}
+
+// Escape text to go into HTML, as element content or a quoted attribute value
+function escapeHtml(value) {
+    return String(value)
+        .replace(/&/g, '&amp;')
+        .replace(/</g, '&lt;')
+        .replace(/>/g, '&gt;')
+        .replace(/"/g, '&quot;')
+        .replace(/'/g, '&#39;');
+}
+
 function enableEditSocials(container, uuid) {
     container.querySelectorAll('.editableSocialValue').forEach(social => {
         social.addEventListener('click', function () {
@@ -406,7 +838,7 @@ function enableEditSocials(container, uuid) {
                             uuid,
                             { "EditContactSocial": [contactId, socialKey, newSocialValue] }]
                     }),
-                });
+                }).then(showUpdateError);
             }
         });
     });
@@ -447,7 +879,11 @@ function enableEditSocials(container, uuid) {
                             { "EditContactSocial": [contactId, socialKey, socialValue] }
                         ]
                     }),
-                }).then(() => {
+                }).then(response => {
+                    if (!response.ok) {
+                        showUpdateError(response);
+                        return;
+                    }
                     this.previousElementSibling.previousElementSibling.value = ''; // Clear the social media name input
                     this.previousElementSibling.value = ''; // Clear the social media handle input
                 });
@@ -456,6 +892,11 @@ function enableEditSocials(container, uuid) {
     });
 }
 
+function showReminder(reminder) {
+    const task = reminder.task;
+    alert(`Task due for ${task.contact}: ${task.title}`);
+}
+
 // Call init to start the application
 init();
 
@@ -465,9 +906,15 @@ const ws = new WebSocket(wsProtocol + "//" + location.host + "/contacts:crdt-crm
 ws.onmessage = event => {
     const data = JSON.parse(event.data);
     console.log(data);
+    if (data.reminder) {
+        showReminder(data.reminder);
+        return;
+    }
+    showLoadError(data.load_error);
     updateContactsAndPeers(data);
     populateContactBookSelector(data.books);
     populateInvites(data.pending_invites);
+    populateContactCards(data.pending_cards, data.books);
     displaySelectedBook();
 };
 